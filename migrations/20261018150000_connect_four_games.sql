@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS connect_four_games (
+    id UUID PRIMARY KEY,
+    width INT NOT NULL,
+    height INT NOT NULL,
+    connect INT NOT NULL,
+    -- Every move since the last reset, oldest first, as [{"team": "cookie", "column": 1}, ...]
+    moves JSONB NOT NULL,
+    -- NULL while the game is in progress or when it ended in a draw
+    winner TEXT CHECK (winner IN ('cookie', 'milk')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS connect_four_games_updated_at ON connect_four_games (updated_at);
@@ -0,0 +1,3 @@
+-- Position the moves were made from, NULL for an empty board
+-- Stored as columns from left to right, each from the bottom up, as [["cookie", "milk", ...], ...]
+ALTER TABLE connect_four_games ADD COLUMN IF NOT EXISTS start JSONB;
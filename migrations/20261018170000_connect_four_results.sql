@@ -0,0 +1,22 @@
+ALTER TABLE connect_four_games
+    ADD COLUMN IF NOT EXISTS cookie_player TEXT,
+    ADD COLUMN IF NOT EXISTS milk_player TEXT;
+
+-- One row each time a game is finished, games are reused after a reset so they can have many
+CREATE TABLE IF NOT EXISTS connect_four_results (
+    id UUID PRIMARY KEY,
+    game_id UUID NOT NULL,
+    cookie_player TEXT,
+    milk_player TEXT,
+    -- NULL for a draw
+    winner TEXT CHECK (winner IN ('cookie', 'milk')),
+    finished_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS connect_four_results_finished_at ON connect_four_results (finished_at);
+
+-- Only the games that are still finished can be recorded, earlier results were overwritten by resets
+INSERT INTO connect_four_results (id, game_id, winner, finished_at)
+SELECT gen_random_uuid(), id, winner, finished_at FROM connect_four_games
+WHERE finished_at IS NOT NULL
+ON CONFLICT DO NOTHING;
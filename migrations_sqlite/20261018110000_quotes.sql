@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS quotes (
+    id BLOB PRIMARY KEY,
+    author TEXT NOT NULL,
+    quote TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    version INTEGER NOT NULL DEFAULT 1,
+    deleted_at TEXT
+);
+
+CREATE TABLE IF NOT EXISTS quote_versions (
+    quote_id BLOB NOT NULL REFERENCES quotes (id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    author TEXT NOT NULL,
+    quote TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (quote_id, version)
+);
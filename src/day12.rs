@@ -1,15 +1,79 @@
-use actix_web::{error, web};
-use anyhow::bail;
+use actix_web::{
+    body::BoxBody,
+    error,
+    http::{
+        header::{self, Accept, ContentType, Header as _},
+        StatusCode,
+    },
+    web, Either, HttpRequest, HttpResponse, Responder,
+};
+use anyhow::{anyhow, bail, Context as _};
+use games::{GameId, Games};
+use notation::Notation;
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
 use std::{fmt::Display, str::FromStr, sync::Mutex};
 use tracing::instrument;
+use uuid::Uuid;
 
-#[derive(Debug, Default)]
+mod ai;
+mod analysis;
+mod games;
+mod html;
+mod leaderboard;
+mod notation;
+mod store;
+mod ws;
+
+/// Seed random boards start from, and go back to on reset
+const DEFAULT_SEED: u64 = 2024;
+
+/// Longest player name allowed, in characters
+const MAX_PLAYER_NAME: usize = 32;
+
+#[derive(Debug, Clone)]
 pub struct Board {
-    /// (0,0) is bottom left columns[0][3] is top left
-    columns: [[CellValue; 4]; 4],
+    /// (0,0) is bottom left columns[0][height - 1] is top left
+    columns: Vec<Vec<CellValue>>,
+    rules: Rules,
+    /// The board before the first move, empty unless a random board was made
+    start: Vec<Vec<CellValue>>,
+    /// Every piece placed since the last reset, oldest first
+    moves: Vec<Move>,
+    /// Kept between random boards so they follow on from each other
+    rng: StdRng,
+    players: Players,
+    /// Set once the result is on the leaderboard so undoing the last move and finishing again
+    /// doesn't count twice
+    result_recorded: bool,
+}
+
+/// Names of the players that joined each team
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+struct Players {
+    cookie: Option<String>,
+    milk: Option<String>,
+}
+
+impl Players {
+    fn get_mut(&mut self, team: Team) -> &mut Option<String> {
+        match team {
+            Team::Cookie => &mut self.cookie,
+            Team::Milk => &mut self.milk,
+        }
+    }
 }
 
-#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Move {
+    team: Team,
+    /// Starts at 1 like in the `place` route
+    column: usize,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum CellValue {
     #[default]
     Empty,
@@ -55,12 +119,30 @@ impl TryFrom<CellValue> for Team {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum Team {
     Cookie,
     Milk,
 }
 
+impl Team {
+    /// Name used in routes and JSON
+    fn name(self) -> &'static str {
+        match self {
+            Team::Cookie => "cookie",
+            Team::Milk => "milk",
+        }
+    }
+
+    fn opponent(self) -> Self {
+        match self {
+            Team::Cookie => Team::Milk,
+            Team::Milk => Team::Cookie,
+        }
+    }
+}
+
 impl FromStr for Team {
     type Err = anyhow::Error;
 
@@ -87,25 +169,231 @@ impl Display for Team {
     }
 }
 
+/// Shape of a board and how many pieces in a row are needed to win
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+struct Rules {
+    width: usize,
+    height: usize,
+    /// Pieces in a row (horizontally, vertically or diagonally) needed to win
+    connect: usize,
+}
+
+impl Default for Rules {
+    /// The board from the challenge
+    fn default() -> Self {
+        Self {
+            width: 4,
+            height: 4,
+            connect: 4,
+        }
+    }
+}
+
+impl Rules {
+    /// Largest width or height allowed, the board is rendered on every request
+    const MAX_SIZE: usize = 16;
+
+    fn validate(&self) -> actix_web::Result<()> {
+        if !(1..=Self::MAX_SIZE).contains(&self.width)
+            || !(1..=Self::MAX_SIZE).contains(&self.height)
+        {
+            return Err(error::ErrorBadRequest(format!(
+                "width and height must be between 1 and {}",
+                Self::MAX_SIZE
+            )));
+        }
+        if !(2..=self.width.max(self.height)).contains(&self.connect) {
+            return Err(error::ErrorBadRequest(
+                "connect must be at least 2 and fit on the board",
+            ));
+        }
+        Ok(())
+    }
+}
+
 impl Board {
     const WALL: &str = "⬜";
     fn new() -> Self {
         Self::default()
     }
 
-    fn new_wrapped() -> web::Data<Mutex<Self>> {
-        web::Data::new(Mutex::new(Self::new()))
+    fn with_rules(rules: Rules) -> Self {
+        let columns = vec![vec![CellValue::Empty; rules.height]; rules.width];
+        Self {
+            start: columns.clone(),
+            columns,
+            moves: vec![],
+            rules,
+            rng: StdRng::seed_from_u64(DEFAULT_SEED),
+            players: Players::default(),
+            result_recorded: false,
+        }
     }
+
+    /// Plays the moves from the starting position (or an empty board), failing if the rules or
+    /// any of the moves aren't allowed
+    fn from_moves(
+        rules: Rules,
+        start: Option<Vec<Vec<CellValue>>>,
+        moves: &[Move],
+    ) -> anyhow::Result<Self> {
+        rules
+            .validate()
+            .map_err(|e| anyhow!("invalid rules: {e}"))?;
+        let mut board = Self::with_rules(rules);
+        if let Some(start) = start {
+            if start.len() != rules.width || start.iter().any(|column| column.len() != rules.height)
+            {
+                bail!("starting position doesn't match the rules");
+            }
+            board.set_start(start);
+        }
+        for (i, played) in moves.iter().enumerate() {
+            let col = played.column.checked_sub(1).context("columns start at 1")?;
+            board
+                .place(played.team, col)
+                .map_err(|e| anyhow!("move {} is not allowed: {e}", i + 1))?;
+        }
+        // It was recorded when the game was played
+        board.result_recorded = board.winner().is_some();
+        Ok(board)
+    }
+
+    /// Starts over from the position given, without any moves
+    fn set_start(&mut self, start: Vec<Vec<CellValue>>) {
+        self.columns = start.clone();
+        self.start = start;
+        self.moves.clear();
+        self.result_recorded = false;
+    }
+
+    /// Starts over from a shared position, `connect` keeps its current value unless given
+    ///
+    /// Fails with 400 Bad Request if one team has placed more than one piece more than the other.
+    /// The moves that led to the position aren't known so either team may move next, even when
+    /// the counts show whose turn it would be
+    fn load(
+        &mut self,
+        Notation(columns): Notation,
+        connect: Option<usize>,
+    ) -> actix_web::Result<()> {
+        let pieces = |team: Team| {
+            columns
+                .iter()
+                .flatten()
+                .filter(|&&cell| cell == team.into())
+                .count()
+        };
+        if pieces(Team::Cookie).abs_diff(pieces(Team::Milk)) > 1 {
+            return Err(error::ErrorBadRequest(
+                "the teams' piece counts can't differ by more than one",
+            ));
+        }
+        let rules = Rules {
+            width: columns.len(),
+            height: columns[0].len(),
+            connect: connect.unwrap_or(self.rules.connect),
+        };
+        rules.validate()?;
+        self.rules = rules;
+        self.set_start(columns);
+        Ok(())
+    }
+
+    /// Fills every cell with a random piece, reseeding first if a seed is given
+    fn randomize(&mut self, seed: Option<u64>) {
+        if let Some(seed) = seed {
+            self.rng = StdRng::seed_from_u64(seed);
+        }
+        let Rules { width, height, .. } = self.rules;
+        let mut start = vec![vec![CellValue::Empty; height]; width];
+        // Top row first, left to right, the order the board is shown in
+        for row in (0..height).rev() {
+            for column in &mut start {
+                column[row] = if self.rng.gen::<bool>() {
+                    CellValue::Cookie
+                } else {
+                    CellValue::Milk
+                };
+            }
+        }
+        self.set_start(start);
+    }
+
+    /// The starting board followed by the board after each move
+    fn replay(&self) -> Vec<ReplayStep> {
+        let mut board = self.clone();
+        board.set_start(self.start.clone());
+        let mut steps = vec![ReplayStep {
+            played: None,
+            board: (&board).into(),
+        }];
+        for &played in &self.moves {
+            board
+                .place(played.team, played.column - 1)
+                .expect("moves were allowed when they were made");
+            steps.push(ReplayStep {
+                played: Some(played),
+                board: (&board).into(),
+            });
+        }
+        steps
+    }
+
     fn row_as_str(&self, row: usize) -> String {
         let mut result = String::new();
-        for col in 0..4 {
-            result.push_str(self.columns[col][row].as_str());
+        for column in &self.columns {
+            result.push_str(column[row].as_str());
         }
         result
     }
 
-    fn reset(&mut self) {
-        self.columns = Default::default();
+    /// Empties the board, changing its size and win length if new rules are given, and goes back
+    /// to the first random board
+    ///
+    /// The players stay for the next game
+    fn reset(&mut self, rules: Option<Rules>) {
+        let players = std::mem::take(&mut self.players);
+        *self = Self::with_rules(rules.unwrap_or(self.rules));
+        self.players = players;
+    }
+
+    /// Fails with 409 Conflict once the game has started
+    fn join(&mut self, team: Team, player: String) -> actix_web::Result<()> {
+        if !self.moves.is_empty() {
+            return Err(error::ErrorConflict(
+                "players can't change once the game has started",
+            ));
+        }
+        *self.players.get_mut(team) = Some(player);
+        Ok(())
+    }
+
+    /// Whether a move finished the game and it hasn't been recorded since the last reset, it is
+    /// then counted as recorded
+    ///
+    /// Random boards that start out finished aren't games anyone played so they aren't recorded
+    fn record_result(&mut self) -> bool {
+        if self.result_recorded || self.moves.is_empty() || self.winner().is_none() {
+            return false;
+        }
+        self.result_recorded = true;
+        true
+    }
+
+    /// The team that has to place next, `None` before the first move when either team may start
+    fn turn(&self) -> Option<Team> {
+        self.moves.last().map(|last| last.team.opponent())
+    }
+
+    /// Fails with 409 Conflict if it is the other team's turn
+    fn check_turn(&self, team: Team) -> actix_web::Result<()> {
+        match self.turn() {
+            Some(turn) if turn != team => {
+                Err(error::ErrorConflict(format!("It is {turn}'s turn.")))
+            }
+            _ => Ok(()),
+        }
     }
 
     fn winner_status(&self) -> Option<String> {
@@ -117,55 +405,64 @@ impl Board {
         }
     }
 
-    /// Outer option represent if there is any winner and inner option represent which team one if any
-    fn winner(&self) -> Option<Option<Team>> {
-        // Check diagonals
-        if self.columns[3][3].is_occupied()
-            && (0..3).all(|i| self.columns[i][i] == self.columns[i + 1][i + 1])
-        {
-            return Some(Some(
-                self.columns[0][0]
-                    .try_into()
-                    .expect("checked that it is not empty in if condition"),
-            ));
+    /// `None` outside of the board
+    fn cell(&self, col: isize, row: isize) -> Option<CellValue> {
+        let col = usize::try_from(col).ok()?;
+        let row = usize::try_from(row).ok()?;
+        self.columns.get(col)?.get(row).copied()
+    }
+
+    /// The team with `connect` pieces in a row starting at (col, row) in the direction given
+    fn line_winner(
+        &self,
+        (col, row): (usize, usize),
+        (dcol, drow): (isize, isize),
+    ) -> Option<Team> {
+        let first = self.columns[col][row];
+        if first.is_empty() {
+            return None;
         }
-        if self.columns[0][3].is_occupied()
-            && (0..3).all(|i| self.columns[i][3 - i] == self.columns[i + 1][3 - i - 1])
-        {
-            return Some(Some(
-                self.columns[0][3]
+        let (col, row) = (col as isize, row as isize);
+        (1..self.rules.connect as isize)
+            .all(|i| self.cell(col + dcol * i, row + drow * i) == Some(first))
+            .then(|| {
+                first
                     .try_into()
-                    .expect("checked that it is not empty in if condition"),
-            ));
-        }
+                    .expect("checked that it is not empty at the start")
+            })
+    }
 
-        // Check each row and column
-        for i in 0..4 {
-            // Check row
-            if self.columns[0][i].is_occupied()
-                && (0..3).all(|col| self.columns[col][i] == self.columns[col + 1][i])
-            {
-                return Some(Some(
-                    self.columns[0][i]
-                        .try_into()
-                        .expect("check that is it not empty in if condition"),
-                ));
-            }
+    /// Outer option represent if there is any winner and inner option represent which team one if any
+    fn winner(&self) -> Option<Option<Team>> {
+        let Rules { width, height, .. } = self.rules;
+        let cells = || (0..width).flat_map(move |col| (0..height).map(move |row| (col, row)));
 
-            // Check column
-            if self.columns[i][3].is_occupied()
-                && (0..3).all(|row| self.columns[i][row] == self.columns[i][row + 1])
-            {
-                return Some(Some(
-                    self.columns[i][3]
-                        .try_into()
-                        .expect("check that is it not empty in if condition"),
-                ));
-            }
+        // Diagonals are checked first then each row and column in turn, so when there is more
+        // than one line (eg. on a filled in board) the same winner is found as on the original
+        // 4x4 board
+        let diagonals = cells().flat_map(|start| [(start, (1, 1)), (start, (1, -1))]);
+        let rows_and_columns = (0..width.max(height)).flat_map(|i| {
+            let row = (0..width)
+                .filter(move |_| i < height)
+                .map(move |col| ((col, i), (1, 0)));
+            let column = (0..height)
+                .filter(move |_| i < width)
+                .map(move |row| ((i, row), (0, 1)));
+            row.chain(column)
+        });
+        if let Some(team) = diagonals
+            .chain(rows_and_columns)
+            .find_map(|(start, direction)| self.line_winner(start, direction))
+        {
+            return Some(Some(team));
         }
 
         // Check for game over but no winner (game over if no spaces in top row)
-        if (0..4).all(|col| self.columns[col][3].is_occupied()) {
+        if self
+            .columns
+            .iter()
+            .all(|column| column[height - 1].is_occupied())
+        {
             return Some(None);
         }
 
@@ -173,28 +470,61 @@ impl Board {
         None
     }
 
+    /// Fails with 400 Bad Request if the column isn't on the board, `col` starts at 0
+    fn check_column(&self, col: usize) -> actix_web::Result<()> {
+        if col >= self.rules.width {
+            return Err(error::ErrorBadRequest(""));
+        }
+        Ok(())
+    }
+
+    /// `col` starts at 0
     fn place(&mut self, team: Team, col: usize) -> actix_web::Result<()> {
+        self.check_column(col)?;
         if self.winner().is_some() {
             return Err(error::ErrorServiceUnavailable(self.to_string()));
         }
+        self.check_turn(team)?;
 
-        for i in 0..4 {
-            if self.columns[col][i].is_empty() {
-                self.columns[col][i] = team.into();
-                return Ok(());
-            }
-        }
+        let Some(cell) = self.columns[col].iter_mut().find(|cell| cell.is_empty()) else {
+            return Err(error::ErrorServiceUnavailable("column full"));
+        };
+        *cell = team.into();
+        self.moves.push(Move {
+            team,
+            column: col + 1,
+        });
+        Ok(())
+    }
+
+    /// Takes back the last move, including one that ended the game
+    fn undo(&mut self) -> actix_web::Result<()> {
+        let last = self
+            .moves
+            .pop()
+            .ok_or_else(|| error::ErrorConflict("no moves to undo"))?;
+        let column = &mut self.columns[last.column - 1];
+        let top = column
+            .iter()
+            .rposition(|cell| cell.is_occupied())
+            .expect("a move was recorded for this column");
+        column[top] = CellValue::Empty;
+        Ok(())
+    }
+}
 
-        Err(error::ErrorServiceUnavailable("column full"))
+impl Default for Board {
+    fn default() -> Self {
+        Self::with_rules(Rules::default())
     }
 }
 
 impl Display for Board {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for row in (0..4).rev() {
+        for row in (0..self.rules.height).rev() {
             writeln!(f, "{}{}{}", Self::WALL, self.row_as_str(row), Self::WALL,)?;
         }
-        writeln!(f, "{}", Self::WALL.repeat(6))?;
+        writeln!(f, "{}", Self::WALL.repeat(self.rules.width + 2))?;
         if let Some(game_ended_status) = self.winner_status() {
             writeln!(f, "{game_ended_status}")
         } else {
@@ -203,41 +533,861 @@ impl Display for Board {
     }
 }
 
-#[instrument]
-async fn show_board(board: web::Data<Mutex<Board>>) -> String {
-    board.lock().unwrap().to_string()
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum GameStatus {
+    InProgress,
+    Won,
+    Draw,
+}
+
+/// The board for bots that would rather not parse the emoji
+#[derive(Debug, Serialize)]
+struct BoardJson {
+    #[serde(flatten)]
+    rules: Rules,
+    /// Top row first, the same way up as the emoji
+    grid: Vec<Vec<CellValue>>,
+    /// `None` once the game is over or before the first move when either team may start
+    turn: Option<Team>,
+    status: GameStatus,
+    winner: Option<Team>,
+    players: Players,
+}
+
+impl From<&Board> for BoardJson {
+    fn from(board: &Board) -> Self {
+        let grid = (0..board.rules.height)
+            .rev()
+            .map(|row| board.columns.iter().map(|column| column[row]).collect())
+            .collect();
+        let (status, winner, turn) = match board.winner() {
+            Some(Some(team)) => (GameStatus::Won, Some(team), None),
+            Some(None) => (GameStatus::Draw, None, None),
+            None => (GameStatus::InProgress, None, board.turn()),
+        };
+        Self {
+            rules: board.rules,
+            grid,
+            turn,
+            status,
+            winner,
+            players: board.players.clone(),
+        }
+    }
+}
+
+/// A board during a replay and the move that led to it
+#[derive(Debug, Serialize)]
+struct ReplayStep {
+    /// `None` for the board at the start
+    #[serde(rename = "move")]
+    played: Option<Move>,
+    board: BoardJson,
+}
+
+/// A board returned by a route, as JSON if the client prefers `application/json` over
+/// `text/plain` and as emoji otherwise
+#[derive(Debug)]
+struct BoardResponse {
+    status: StatusCode,
+    json: BoardJson,
+    text: String,
+}
+
+impl From<&Board> for BoardResponse {
+    fn from(board: &Board) -> Self {
+        Self {
+            status: StatusCode::OK,
+            json: board.into(),
+            text: board.to_string(),
+        }
+    }
+}
+
+impl BoardResponse {
+    /// 503 Service Unavailable with the board if the game is over, so no more pieces can be placed
+    fn game_over(board: &Board) -> Option<Self> {
+        board.winner().is_some().then(|| Self {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            ..board.into()
+        })
+    }
+}
+
+fn prefers_json(req: &HttpRequest) -> bool {
+    let Ok(accept) = Accept::parse(req) else {
+        return false;
+    };
+    accept
+        .ranked()
+        .into_iter()
+        .map(|mime| mime.essence_str().to_string())
+        .find(|mime| mime == "application/json" || mime == "text/plain")
+        .is_some_and(|mime| mime == "application/json")
+}
+
+impl Responder for BoardResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let mut response = HttpResponse::build(self.status);
+        response.insert_header((header::VARY, "Accept"));
+        if prefers_json(req) {
+            response.json(self.json)
+        } else {
+            response
+                .content_type(ContentType::plaintext())
+                .body(self.text)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PlacePath {
+    team: String,
+    col: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AiQuery {
+    /// Moves to look ahead, higher is harder to beat
+    depth: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnalyzeQuery {
+    /// Needed before the first move when either team may start
+    team: Option<Team>,
+    /// Moves to look ahead
+    depth: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaceQuery {
+    /// Have the AI place for the other team straight after
+    #[serde(default)]
+    reply: bool,
+    /// Moves the AI looks ahead when replying
+    depth: Option<u32>,
+}
+
+/// Fails with 400 Bad Request if the depth is outside of what the AI supports
+fn ai_depth(depth: Option<u32>) -> actix_web::Result<u32> {
+    let depth = depth.unwrap_or(ai::DEFAULT_DEPTH);
+    if !(1..=ai::MAX_DEPTH).contains(&depth) {
+        return Err(error::ErrorBadRequest(format!(
+            "depth must be between 1 and {}",
+            ai::MAX_DEPTH
+        )));
+    }
+    Ok(depth)
+}
+
+#[derive(Debug, Deserialize)]
+struct RandomBoardQuery {
+    /// Start a new sequence of random boards from this seed
+    seed: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct NewGame {
+    id: Uuid,
+}
+
+/// Rules not given keep their current value (or the default for a new game)
+#[derive(Debug, Deserialize)]
+struct RulesQuery {
+    width: Option<usize>,
+    height: Option<usize>,
+    connect: Option<usize>,
+}
+
+impl RulesQuery {
+    /// `None` if no rules were given
+    fn apply_to(&self, current: Rules) -> actix_web::Result<Option<Rules>> {
+        if self.width.is_none() && self.height.is_none() && self.connect.is_none() {
+            return Ok(None);
+        }
+        let rules = Rules {
+            width: self.width.unwrap_or(current.width),
+            height: self.height.unwrap_or(current.height),
+            connect: self.connect.unwrap_or(current.connect),
+        };
+        rules.validate()?;
+        Ok(Some(rules))
+    }
+}
+
+#[instrument(skip(games))]
+async fn create_game(
+    web::Query(query): web::Query<RulesQuery>,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<HttpResponse> {
+    let rules = query.apply_to(Rules::default())?.unwrap_or_default();
+    let id = games.lock().unwrap().create(Board::with_rules(rules))?;
+    Ok(HttpResponse::Created().json(NewGame { id }))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BoardFormat {
+    /// See [`Notation`]
+    Notation,
+}
+
+#[derive(Debug, Deserialize)]
+struct BoardQuery {
+    /// Overrides the `Accept` header
+    format: Option<BoardFormat>,
+}
+
+#[instrument(skip(games))]
+async fn show_board(
+    game: GameId,
+    web::Query(query): web::Query<BoardQuery>,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<Either<BoardResponse, String>> {
+    let mut guard = games.lock().unwrap();
+    let board = guard.board(&game)?;
+    Ok(match query.format {
+        Some(BoardFormat::Notation) => Either::Right(Notation::from(board).to_string()),
+        None => Either::Left(board.into()),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct LoadQuery {
+    connect: Option<usize>,
+}
+
+/// Replaces the board with a position in [`Notation`], the moves are made from there
+#[instrument(skip(games))]
+async fn load_board(
+    game: GameId,
+    web::Query(query): web::Query<LoadQuery>,
+    body: String,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<BoardResponse> {
+    let position: Notation = body.parse().map_err(error::ErrorBadRequest)?;
+    games.lock().unwrap().update(&game, |board| {
+        board.load(position, query.connect)?;
+        Ok((&*board).into())
+    })
+}
+
+#[instrument(skip(games))]
+async fn reset(
+    game: GameId,
+    web::Query(query): web::Query<RulesQuery>,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<BoardResponse> {
+    games.lock().unwrap().update(&game, |board| {
+        let rules = query.apply_to(board.rules)?;
+        board.reset(rules);
+        Ok((&*board).into())
+    })
+}
+
+#[instrument(skip(games))]
+async fn random_board(
+    game: GameId,
+    web::Query(query): web::Query<RandomBoardQuery>,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<BoardResponse> {
+    games.lock().unwrap().update(&game, |board| {
+        board.randomize(query.seed);
+        Ok((&*board).into())
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinPath {
+    team: String,
+    player: String,
+}
+
+#[instrument(skip(games))]
+async fn join(
+    game: GameId,
+    path: web::Path<JoinPath>,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<BoardResponse> {
+    let JoinPath { team, player } = path.into_inner();
+    let team: Team = team.parse().map_err(error::ErrorBadRequest)?;
+    let player = player.trim().to_string();
+    if player.is_empty() || player.chars().count() > MAX_PLAYER_NAME {
+        return Err(error::ErrorBadRequest(format!(
+            "player names must be between 1 and {MAX_PLAYER_NAME} characters"
+        )));
+    }
+    games.lock().unwrap().update(&game, |board| {
+        board.join(team, player)?;
+        Ok((&*board).into())
+    })
+}
+
+#[instrument(skip(pool))]
+async fn leaderboard(pool: web::Data<PgPool>) -> actix_web::Result<HttpResponse> {
+    let results = store::results(&pool).await?;
+    Ok(HttpResponse::Ok().json(leaderboard::Leaderboard::from_results(&results)))
+}
+
+#[instrument(skip(games))]
+async fn moves(game: GameId, games: web::Data<Mutex<Games>>) -> actix_web::Result<HttpResponse> {
+    let mut guard = games.lock().unwrap();
+    Ok(HttpResponse::Ok().json(&guard.board(&game)?.moves))
 }
 
-#[instrument]
-async fn reset(board: web::Data<Mutex<Board>>) -> String {
-    let mut guard = board.lock().unwrap();
-    guard.reset();
-    guard.to_string()
+#[instrument(skip(games))]
+async fn undo(game: GameId, games: web::Data<Mutex<Games>>) -> actix_web::Result<BoardResponse> {
+    games.lock().unwrap().update(&game, |board| {
+        board.undo()?;
+        Ok((&*board).into())
+    })
 }
 
-#[instrument]
+#[instrument(skip(games))]
 async fn place(
-    path: web::Path<(String, String)>,
-    board: web::Data<Mutex<Board>>,
-) -> actix_web::Result<String> {
-    let (team, col) = path.into_inner();
+    game: GameId,
+    path: web::Path<PlacePath>,
+    web::Query(query): web::Query<PlaceQuery>,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<BoardResponse> {
+    let PlacePath { team, col } = path.into_inner();
     let team: Team = team.parse().map_err(error::ErrorBadRequest)?;
     let col: usize = col.parse().map_err(error::ErrorBadRequest)?;
-    let mut guard = board.lock().unwrap();
-    if !(1..=4).contains(&col) {
-        return Err(error::ErrorBadRequest(""));
+    let col = col
+        .checked_sub(1)
+        .ok_or_else(|| error::ErrorBadRequest(""))?;
+    let depth = ai_depth(query.depth)?;
+    let game_over = {
+        let mut guard = games.lock().unwrap();
+        let board = guard.board(&game)?;
+        board.check_column(col)?;
+        if let Some(response) = BoardResponse::game_over(board) {
+            return Ok(response);
+        }
+        guard.update(&game, |board| {
+            board.place(team, col)?;
+            Ok(board.winner().is_some())
+        })?
+    };
+    if !query.reply || game_over {
+        let mut guard = games.lock().unwrap();
+        return Ok(guard.board(&game)?.into());
     }
-    guard.place(team, col - 1)?;
-    Ok(guard.to_string())
+    ai_place(game, team.opponent(), depth, &games).await
 }
 
-pub fn scope() -> actix_web::Scope {
-    web::scope("/12")
+#[instrument(skip(games))]
+async fn ai(
+    game: GameId,
+    path: web::Path<String>,
+    web::Query(query): web::Query<AiQuery>,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<BoardResponse> {
+    let team: Team = path.into_inner().parse().map_err(error::ErrorBadRequest)?;
+    let depth = ai_depth(query.depth)?;
+    ai_place(game, team, depth, &games).await
+}
+
+/// Scores every column the team to move can place in, the lock isn't held while searching
+#[instrument(skip(games))]
+async fn analyze(
+    game: GameId,
+    web::Query(query): web::Query<AnalyzeQuery>,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<HttpResponse> {
+    let depth = ai_depth(query.depth)?;
+    let board = games.lock().unwrap().board(&game)?.clone();
+    if board.winner().is_some() {
+        return Err(error::ErrorConflict("the game is over"));
+    }
+    let team = match (query.team, board.turn()) {
+        (Some(team), _) => {
+            board.check_turn(team)?;
+            team
+        }
+        (None, Some(turn)) => turn,
+        (None, None) => {
+            return Err(error::ErrorBadRequest(
+                "either team may start, pass the team to analyze for",
+            ))
+        }
+    };
+    let analysis = web::block(move || board.analyze(team, depth))
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(analysis))
+}
+
+/// Places for the team where the AI chooses, the lock isn't held while the AI is choosing
+async fn ai_place(
+    game: GameId,
+    team: Team,
+    depth: u32,
+    games: &Mutex<Games>,
+) -> actix_web::Result<BoardResponse> {
+    let board = {
+        let mut guard = games.lock().unwrap();
+        let board = guard.board(&game)?;
+        if let Some(response) = BoardResponse::game_over(board) {
+            return Ok(response);
+        }
+        board.check_turn(team)?;
+        board.clone()
+    };
+    let (rules, start, moves) = (board.rules, board.start.clone(), board.moves.clone());
+    let col = web::block(move || board.best_move(team, depth))
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    games.lock().unwrap().update(&game, |board| {
+        let Some(col) = col else {
+            return Err(error::ErrorServiceUnavailable(board.to_string()));
+        };
+        if board.rules != rules || board.start != start || board.moves != moves {
+            return Err(error::ErrorConflict(
+                "the board changed while the AI was choosing a move",
+            ));
+        }
+        board.place(team, col)?;
+        Ok((&*board).into())
+    })
+}
+
+/// Every board from the start of the game (or the last reset), also for games that are no longer
+/// in memory as long as they were saved
+#[instrument(skip(games, pool))]
+async fn replay(
+    game: GameId,
+    games: web::Data<Mutex<Games>>,
+    pool: web::Data<PgPool>,
+) -> actix_web::Result<HttpResponse> {
+    let board = games.lock().unwrap().board(&game).ok().cloned();
+    let board = match board {
+        Some(board) => board,
+        None => store::load(&pool, &game.0)
+            .await?
+            .context("game not found")
+            .map_err(error::ErrorNotFound)?,
+    };
+    Ok(HttpResponse::Ok().json(board.replay()))
+}
+
+fn html_response(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(body)
+}
+
+/// The board for browsers, which keeps itself up to date with the fragments below
+#[instrument(skip(games))]
+async fn view(game: GameId, games: web::Data<Mutex<Games>>) -> actix_web::Result<HttpResponse> {
+    let mut guard = games.lock().unwrap();
+    Ok(html_response(html::page(&game, guard.board(&game)?)?))
+}
+
+#[instrument(skip(games))]
+async fn board_fragment(
+    game: GameId,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<HttpResponse> {
+    let mut guard = games.lock().unwrap();
+    Ok(html_response(html::board(
+        &game,
+        guard.board(&game)?,
+        None,
+    )?))
+}
+
+/// Moves that aren't allowed are shown on the board rather than failing so htmx swaps it in
+#[instrument(skip(games))]
+async fn place_fragment(
+    game: GameId,
+    path: web::Path<PlacePath>,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<HttpResponse> {
+    let PlacePath { team, col } = path.into_inner();
+    let team: Team = team.parse().map_err(error::ErrorBadRequest)?;
+    let col: usize = col.parse().map_err(error::ErrorBadRequest)?;
+    let col = col
+        .checked_sub(1)
+        .ok_or_else(|| error::ErrorBadRequest(""))?;
+    let mut guard = games.lock().unwrap();
+    let error = guard
+        .update(&game, |board| board.place(team, col))
+        .err()
+        .map(|e| e.to_string());
+    Ok(html_response(html::board(
+        &game,
+        guard.board(&game)?,
+        error,
+    )?))
+}
+
+#[instrument(skip(games))]
+async fn reset_fragment(
+    game: GameId,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<HttpResponse> {
+    let mut guard = games.lock().unwrap();
+    guard.update(&game, |board| {
+        board.reset(None);
+        Ok(())
+    })?;
+    Ok(html_response(html::board(
+        &game,
+        guard.board(&game)?,
+        None,
+    )?))
+}
+
+/// Watch the board and play over a WebSocket, see [`ws::run`]
+#[instrument(skip(req, body, games))]
+async fn watch(
+    req: HttpRequest,
+    body: web::Payload,
+    game: GameId,
+    games: web::Data<Mutex<Games>>,
+) -> actix_web::Result<HttpResponse> {
+    let (updates, current) = games.lock().unwrap().watch(&game)?;
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(ws::run(
+        session,
+        messages,
+        updates,
+        current,
+        game,
+        games.clone(),
+    ));
+    Ok(response)
+}
+
+/// Routes for a single game, used both with and without a game id
+fn game_routes(scope: actix_web::Scope) -> actix_web::Scope {
+    scope
         .route("/board", web::get().to(show_board))
+        .route("/board", web::put().to(load_board))
         .route("/reset", web::post().to(reset))
         .route("/place/{team}/{col}", web::post().to(place))
+        .route("/random-board", web::get().to(random_board))
+        .route("/join/{team}/{player}", web::post().to(join))
+        .route("/moves", web::get().to(moves))
+        .route("/undo", web::post().to(undo))
+        .route("/ai/{team}", web::post().to(ai))
+        .route("/analyze", web::get().to(analyze))
+        .route("/replay", web::get().to(replay))
+        .route("/ws", web::get().to(watch))
+        .route("/view", web::get().to(view))
+        .route("/fragments/board", web::get().to(board_fragment))
+        .route(
+            "/fragments/place/{team}/{col}",
+            web::post().to(place_fragment),
+        )
+        .route("/fragments/reset", web::post().to(reset_fragment))
 }
 
-pub fn app_data() -> web::Data<Mutex<Board>> {
-    Board::new_wrapped()
+pub fn scope() -> actix_web::Scope {
+    // The routes without a game id use the default game
+    game_routes(web::scope("/12"))
+        .route("/games", web::post().to(create_game))
+        .route("/leaderboard", web::get().to(leaderboard))
+        .service(game_routes(web::scope("/games/{id}")))
+}
+
+/// See [`Games::load`] for how games are saved
+pub async fn app_data(pool: PgPool) -> web::Data<Mutex<Games>> {
+    Games::load(pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+
+    use super::*;
+
+    /// A board with the pieces from `position` in [`Notation`] and no moves
+    fn board(connect: usize, position: &str) -> Board {
+        let Notation(columns) = position.parse().unwrap();
+        let mut board = Board::with_rules(Rules {
+            width: columns.len(),
+            height: columns[0].len(),
+            connect,
+        });
+        board.set_start(columns);
+        board
+    }
+
+    fn status(error: actix_web::Error) -> StatusCode {
+        error.as_response_error().status_code()
+    }
+
+    /// The winner as the original code found it, only for 4x4 boards
+    #[allow(clippy::needless_range_loop)]
+    fn original_winner(board: &Board) -> Option<Option<Team>> {
+        let c = &board.columns;
+        let team = |cell: CellValue| Some(Some(cell.try_into().unwrap()));
+        if c[3][3].is_occupied() && (0..3).all(|i| c[i][i] == c[i + 1][i + 1]) {
+            return team(c[0][0]);
+        }
+        if c[0][3].is_occupied() && (0..3).all(|i| c[i][3 - i] == c[i + 1][3 - i - 1]) {
+            return team(c[0][3]);
+        }
+        for i in 0..4 {
+            if c[0][i].is_occupied() && (0..3).all(|col| c[col][i] == c[col + 1][i]) {
+                return team(c[0][i]);
+            }
+            if c[i][3].is_occupied() && (0..3).all(|row| c[i][row] == c[i][row + 1]) {
+                return team(c[i][3]);
+            }
+        }
+        if (0..4).all(|col| c[col][3].is_occupied()) {
+            return Some(None);
+        }
+        None
+    }
+
+    #[test]
+    fn horizontal() {
+        let board = board(4, "....../c...../c...../c...../c...../....../......");
+        assert_eq!(board.winner(), Some(Some(Team::Cookie)));
+    }
+
+    #[test]
+    fn vertical() {
+        let board = board(4, "....../....../....../mmmm../....../....../......");
+        assert_eq!(board.winner(), Some(Some(Team::Milk)));
+    }
+
+    #[test]
+    fn diagonal_up() {
+        let board = board(4, "c.../mc../mmc./mmmc");
+        assert_eq!(board.winner(), Some(Some(Team::Cookie)));
+    }
+
+    #[test]
+    fn diagonal_down() {
+        let board = board(4, "cccm/ccm./cm../m...");
+        assert_eq!(board.winner(), Some(Some(Team::Milk)));
+    }
+
+    #[test]
+    fn connect_three() {
+        let board = board(3, "....../m...../m...../m...../....../....../......");
+        assert_eq!(board.winner(), Some(Some(Team::Milk)));
+    }
+
+    #[test]
+    fn edges_of_a_wide_board() {
+        // Bottom right
+        let bottom = board(4, "....../....../....../c...../c...../c...../c.....");
+        assert_eq!(bottom.winner(), Some(Some(Team::Cookie)));
+        // Top right
+        let top = board(4, "....../....../....../....../....../....../mmcccc");
+        assert_eq!(top.winner(), Some(Some(Team::Cookie)));
+        // Ending in the top right corner
+        let diagonal = board(4, "....../....../....../mmc.../cmmc../mccmc./ccmmcc");
+        assert_eq!(diagonal.winner(), Some(Some(Team::Cookie)));
+        // Lines don't wrap around or run off the edge
+        let short = board(4, "c...../....../....../....../c...../c...../c.....");
+        assert_eq!(short.winner(), None);
+        let tall = board(4, "....../....../....../....../....../....../mmmccc");
+        assert_eq!(tall.winner(), None);
+    }
+
+    #[test]
+    fn full_board_draw() {
+        let board = board(4, "ccmm/mmcc/ccmm/mmcc");
+        assert_eq!(board.winner(), Some(None));
+        assert_eq!(board.winner_status().as_deref(), Some("No winner."));
+    }
+
+    #[test]
+    fn in_progress() {
+        assert_eq!(Board::new().winner(), None);
+        let board = board(4, "ccm./mmc./ccm./mmc.");
+        assert_eq!(board.winner(), None);
+    }
+
+    #[test]
+    fn same_precedence_as_the_original_4x4_board() {
+        // Lower rows and columns further left are found first
+        assert_eq!(
+            board(4, "mcm./mcm./mcm./mcm.").winner(),
+            Some(Some(Team::Milk))
+        );
+        assert_eq!(
+            board(4, "cccc/..../mmmm/....").winner(),
+            Some(Some(Team::Cookie))
+        );
+
+        // Random boards often have more than one line
+        let mut random = Board::new();
+        for _ in 0..1_000 {
+            random.randomize(None);
+            assert_eq!(random.winner(), original_winner(&random), "{random}");
+        }
+    }
+
+    #[test]
+    fn validate_rules() {
+        let rules = |width, height, connect| Rules {
+            width,
+            height,
+            connect,
+        };
+        assert!(Rules::default().validate().is_ok());
+        assert!(rules(7, 6, 4).validate().is_ok());
+        assert!(rules(7, 6, 7).validate().is_ok());
+        assert!(rules(1, 16, 2).validate().is_ok());
+        for invalid in [
+            rules(0, 6, 4),
+            rules(7, 0, 4),
+            rules(17, 6, 4),
+            rules(7, 17, 4),
+            rules(7, 6, 1),
+            rules(7, 6, 8),
+        ] {
+            let e = invalid.validate().unwrap_err();
+            assert_eq!(status(e), StatusCode::BAD_REQUEST, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn place_bounds() {
+        let mut board = Board::with_rules(Rules {
+            width: 7,
+            height: 2,
+            connect: 4,
+        });
+        let e = board.place(Team::Cookie, 7).unwrap_err();
+        assert_eq!(status(e), StatusCode::BAD_REQUEST);
+        assert!(board.moves.is_empty());
+
+        board.place(Team::Cookie, 6).unwrap();
+        board.place(Team::Milk, 6).unwrap();
+        assert_eq!(board.columns[6], [CellValue::Cookie, CellValue::Milk]);
+        let e = board.place(Team::Cookie, 6).unwrap_err();
+        assert_eq!(status(e), StatusCode::SERVICE_UNAVAILABLE);
+        let e = board.place(Team::Milk, 0).unwrap_err();
+        assert_eq!(status(e), StatusCode::CONFLICT);
+        assert_eq!(board.moves.len(), 2);
+    }
+
+    #[test]
+    fn load_checks_piece_counts() {
+        let mut board = Board::new();
+        board.place(Team::Cookie, 0).unwrap();
+        for impossible in ["cccc/..../..../....", "c.../c.../m.../c..."] {
+            let e = board.load(impossible.parse().unwrap(), None).unwrap_err();
+            assert_eq!(status(e), StatusCode::BAD_REQUEST, "{impossible}");
+        }
+        assert_eq!(board.moves.len(), 1);
+
+        board
+            .load("cmc/m../.../c..".parse().unwrap(), Some(3))
+            .unwrap();
+        assert_eq!(board.to_string().lines().next(), Some("⬜🍪⬛⬛⬛⬜"));
+        assert!(board.moves.is_empty());
+        assert_eq!(board.turn(), None);
+        assert!(board.check_turn(Team::Cookie).is_ok());
+        assert!(board.check_turn(Team::Milk).is_ok());
+    }
+
+    #[test]
+    fn place_after_the_game_is_over() {
+        let mut board = board(4, "c.../c.../c.../c...");
+        let e = board.place(Team::Milk, 0).unwrap_err();
+        assert_eq!(status(e), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn ai_takes_an_immediate_win() {
+        let board = board(4, "mm..../ccc.../m...../....../....../....../......");
+        assert_eq!(board.best_move(Team::Cookie, ai::DEFAULT_DEPTH), Some(1));
+        assert_eq!(board.best_move(Team::Cookie, 1), Some(1));
+    }
+
+    #[test]
+    fn ai_blocks_an_immediate_win() {
+        let board = board(4, "....../....../m...../....../mm..../....../ccc...");
+        assert_eq!(board.best_move(Team::Milk, ai::DEFAULT_DEPTH), Some(6));
+        // Rather than making a line of three it can't finish in time
+        assert_eq!(board.best_move(Team::Milk, 2), Some(6));
+    }
+
+    #[test]
+    fn ai_has_no_move_when_the_game_is_over() {
+        let full = board(4, "ccmm/mmcc/ccmm/mmcc");
+        assert_eq!(full.best_move(Team::Cookie, ai::DEFAULT_DEPTH), None);
+        let won = board(4, "c.../c.../c.../c...");
+        assert_eq!(won.best_move(Team::Milk, ai::DEFAULT_DEPTH), None);
+    }
+
+    #[test]
+    fn ai_stays_within_the_position_budget() {
+        let board = Board::with_rules(Rules {
+            width: 16,
+            height: 16,
+            connect: 4,
+        });
+        assert!(board.search_depth(ai::MAX_DEPTH) < ai::MAX_DEPTH);
+        ai::POSITIONS_SEARCHED.with(|positions| positions.set(0));
+        assert!(board.best_move(Team::Cookie, ai::MAX_DEPTH).is_some());
+        let searched = ai::POSITIONS_SEARCHED.with(|positions| positions.get());
+        assert!(searched <= ai::POSITION_BUDGET, "searched {searched}");
+    }
+
+    #[test]
+    fn analysis_finds_a_win_in_one() {
+        let board = board(4, "ccc.../mm..../m...../....../....../....../......");
+        let analysis = serde_json::to_value(board.analyze(Team::Cookie, 4)).unwrap();
+        assert_eq!(analysis["columns"][0]["outcome"], "win");
+        assert_eq!(analysis["columns"][0]["moves"], 1);
+        assert_eq!(
+            analysis["forced_win"],
+            serde_json::json!({"team": "cookie", "moves": 1, "column": 1})
+        );
+    }
+
+    #[test]
+    fn analysis_finds_a_forced_loss() {
+        // Cookie can finish the bottom row at either end whichever Milk blocks
+        let board = board(4, "m...../....../c...../c...../c...../....../m.....");
+        let analysis = serde_json::to_value(board.analyze(Team::Milk, 4)).unwrap();
+        let columns = analysis["columns"].as_array().unwrap();
+        assert_eq!(columns.len(), 7);
+        assert!(columns.iter().all(|column| column["outcome"] == "loss"));
+        assert_eq!(
+            analysis["forced_win"],
+            serde_json::json!({"team": "cookie", "moves": 2, "column": null})
+        );
+    }
+
+    #[test]
+    fn analysis_finds_a_double_threat() {
+        // An open three in the bottom row can't be blocked at both ends
+        let board = board(4, "m...../....../c...../c...../....../....../m.....");
+        let analysis = serde_json::to_value(board.analyze(Team::Cookie, 4)).unwrap();
+        let forced_win = &analysis["forced_win"];
+        assert_eq!(forced_win["team"], "cookie");
+        assert_eq!(forced_win["moves"], 3);
+        assert!([2, 5].contains(&forced_win["column"].as_u64().unwrap()));
+        assert_eq!(analysis["threats"]["cookie"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn analysis_of_a_finished_game() {
+        for position in ["c.../c.../c.../c...", "ccmm/mmcc/ccmm/mmcc"] {
+            let analysis = serde_json::to_value(board(4, position).analyze(Team::Milk, 4)).unwrap();
+            assert_eq!(analysis["columns"], serde_json::json!([]), "{position}");
+            assert_eq!(
+                analysis["forced_win"],
+                serde_json::Value::Null,
+                "{position}"
+            );
+        }
+    }
 }
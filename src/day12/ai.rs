@@ -0,0 +1,183 @@
+use super::{Board, CellValue, Team};
+#[cfg(test)]
+use std::cell::Cell;
+
+/// Deepest search allowed, each extra move multiplies the work by up to the board width
+pub const MAX_DEPTH: u32 = 6;
+
+/// Moves looked ahead unless a depth is given
+pub const DEFAULT_DEPTH: u32 = 4;
+
+/// Rough limit on the positions searched, the depth is lowered on wide boards to stay under it
+pub const POSITION_BUDGET: usize = 2_000_000;
+
+#[cfg(test)]
+thread_local! {
+    /// Moves tried by searches on this thread, so tests can check the budget is kept to
+    pub static POSITIONS_SEARCHED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Score of a won position, larger than any score [`evaluate`] can give
+pub const WIN: i64 = 1_000_000_000;
+
+/// Score of the position for `team`, higher is better
+///
+/// Every window of `connect` cells that only one team has pieces in counts towards that team, more
+/// so the more pieces it has
+fn evaluate(board: &Board, team: Team) -> i64 {
+    let own = CellValue::from(team);
+    let opponent = CellValue::from(team.opponent());
+    let connect = board.rules.connect as isize;
+    let mut score = 0;
+    for col in 0..board.rules.width as isize {
+        for row in 0..board.rules.height as isize {
+            for (dcol, drow) in [(1, 0), (0, 1), (1, 1), (1, -1)] {
+                let mut own_count = 0;
+                let mut opponent_count = 0;
+                for i in 0..connect {
+                    match board.cell(col + dcol * i, row + drow * i) {
+                        None => {
+                            // Window doesn't fit on the board
+                            own_count = 0;
+                            opponent_count = 0;
+                            break;
+                        }
+                        Some(cell) if cell == own => own_count += 1,
+                        Some(cell) if cell == opponent => opponent_count += 1,
+                        Some(_) => {}
+                    }
+                }
+                match (own_count, opponent_count) {
+                    (0, 0) => {}
+                    (n, 0) => score += n * n,
+                    (0, n) => score -= n * n,
+                    _ => {}
+                }
+            }
+        }
+    }
+    score
+}
+
+/// Columns that aren't full, closest to the centre first as those moves tend to be best which
+/// lets alpha-beta pruning skip more
+fn move_order(board: &Board) -> Vec<usize> {
+    let width = board.rules.width;
+    let mut columns: Vec<usize> = (0..width)
+        .filter(|&col| {
+            board.columns[col]
+                .last()
+                .is_some_and(|cell| cell.is_empty())
+        })
+        .collect();
+    columns.sort_by_key(|&col| (2 * col).abs_diff(width - 1));
+    columns
+}
+
+/// Places the piece without any of the checks [`Board::place`] does, returning its row
+fn drop_piece(board: &mut Board, team: Team, col: usize) -> usize {
+    let row = board.columns[col]
+        .iter()
+        .position(|cell| cell.is_empty())
+        .expect("only columns with space are tried");
+    board.columns[col][row] = team.into();
+    row
+}
+
+/// Whether the piece at (col, row) is part of `connect` pieces in a row
+///
+/// Much cheaper than [`Board::winner`] as only lines through the new piece need to be checked
+pub fn completes_line(board: &Board, col: usize, row: usize) -> bool {
+    let piece = board.columns[col][row];
+    let (col, row) = (col as isize, row as isize);
+    let connect = board.rules.connect as isize;
+    [(1, 0), (0, 1), (1, 1), (1, -1)]
+        .into_iter()
+        .any(|(dcol, drow)| {
+            let count_towards = |sign: isize| {
+                (1..connect)
+                    .take_while(|i| {
+                        board.cell(col + sign * dcol * i, row + sign * drow * i) == Some(piece)
+                    })
+                    .count() as isize
+            };
+            1 + count_towards(1) + count_towards(-1) >= connect
+        })
+}
+
+/// Score of placing in the column for the team, the board is left as it was
+fn score_move(board: &mut Board, team: Team, col: usize, depth: u32, alpha: i64, beta: i64) -> i64 {
+    #[cfg(test)]
+    POSITIONS_SEARCHED.with(|positions| positions.set(positions.get() + 1));
+    let row = drop_piece(board, team, col);
+    let score = if completes_line(board, col, row) {
+        // Wins found with more depth remaining are sooner so they score higher
+        WIN + i64::from(depth)
+    } else {
+        -negamax(board, team.opponent(), depth - 1, -beta, -alpha)
+    };
+    board.columns[col][row] = CellValue::Empty;
+    score
+}
+
+/// Minimax in its negamax form with alpha-beta pruning, scored for the team about to move
+///
+/// Wins score [`WIN`] plus the depth remaining when they happen so sooner wins score higher
+pub fn negamax(board: &mut Board, team: Team, depth: u32, mut alpha: i64, beta: i64) -> i64 {
+    if depth == 0 {
+        return evaluate(board, team);
+    }
+    let columns = move_order(board);
+    if columns.is_empty() {
+        // Board is full without a winner
+        return 0;
+    }
+
+    let mut best = -WIN * 2;
+    for col in columns {
+        let score = score_move(board, team, col, depth, alpha, beta);
+        best = best.max(score);
+        alpha = alpha.max(score);
+        if alpha >= beta {
+            break;
+        }
+    }
+    best
+}
+
+impl Board {
+    /// The depth lowered until searching it stays within [`POSITION_BUDGET`]
+    pub(super) fn search_depth(&self, depth: u32) -> u32 {
+        let mut depth = depth;
+        while depth > 1
+            && self
+                .rules
+                .width
+                .checked_pow(depth)
+                .is_none_or(|positions| positions > POSITION_BUDGET)
+        {
+            depth -= 1;
+        }
+        depth
+    }
+
+    /// Column (starting at 0) the team should place in looking up to `depth` moves ahead, `None`
+    /// if the game is over
+    pub(super) fn best_move(&self, team: Team, depth: u32) -> Option<usize> {
+        if self.winner().is_some() {
+            return None;
+        }
+        let depth = self.search_depth(depth);
+        let mut board = self.clone();
+        let mut best = None;
+        let mut alpha = -WIN * 2;
+        for col in move_order(&board) {
+            let score = score_move(&mut board, team, col, depth, alpha, WIN * 2);
+            if best.is_none() || score > alpha {
+                alpha = score;
+                best = Some(col);
+            }
+        }
+        best
+    }
+}
@@ -0,0 +1,192 @@
+use serde::Serialize;
+
+use super::{ai, Board, CellValue, Team};
+
+/// How a move turns out with best play from both teams, as far as the search could see
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Win,
+    Loss,
+    Draw,
+    /// The game doesn't end within the search depth
+    Unknown,
+}
+
+#[derive(Debug, Serialize)]
+struct ColumnAnalysis {
+    /// Starts at 1 like in the `place` route
+    column: usize,
+    outcome: Outcome,
+    /// Moves by both teams until the game ends including this one, `None` when unknown
+    moves: Option<u32>,
+    /// How good the position looks when the outcome is unknown, higher is better
+    score: Option<i64>,
+    /// Cells where the team would complete a line after this move
+    threats: usize,
+}
+
+/// A cell that would complete a line for a team
+#[derive(Debug, Serialize)]
+struct Threat {
+    /// Starts at 1 like in the `place` route
+    column: usize,
+    /// Starts at 1 at the bottom
+    row: usize,
+    /// The cell is the next one to be filled in its column
+    playable: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Threats {
+    cookie: Vec<Threat>,
+    milk: Vec<Threat>,
+}
+
+/// A team that wins however the other team plays
+#[derive(Debug, Serialize)]
+struct ForcedWin {
+    team: Team,
+    /// Moves by both teams until the win
+    moves: u32,
+    /// Column to place in when it is the winning team's turn
+    column: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Analysis {
+    team: Team,
+    /// Moves looked ahead, lower than asked for on wide boards
+    depth: u32,
+    columns: Vec<ColumnAnalysis>,
+    threats: Threats,
+    forced_win: Option<ForcedWin>,
+}
+
+/// Reads the outcome from a [`ai::negamax`] score of a move searched `depth` moves deep
+///
+/// A draw is only certain when the board fills up within the search, which needs no more than
+/// `depth` empty cells
+fn outcome(score: i64, depth: u32, empty: usize) -> (Outcome, Option<u32>, Option<i64>) {
+    let moves_until = |score: i64| Some(depth + 1 - (score - ai::WIN) as u32);
+    if score > ai::WIN / 2 {
+        (Outcome::Win, moves_until(score), None)
+    } else if score < -ai::WIN / 2 {
+        (Outcome::Loss, moves_until(-score), None)
+    } else if empty <= depth as usize {
+        (Outcome::Draw, Some(empty as u32), None)
+    } else {
+        (Outcome::Unknown, None, Some(score))
+    }
+}
+
+impl Board {
+    fn threats(&self, team: Team) -> Vec<Threat> {
+        let mut board = self.clone();
+        let mut threats = vec![];
+        for col in 0..self.rules.width {
+            let next = self.columns[col].iter().position(|cell| cell.is_empty());
+            for row in 0..self.rules.height {
+                if self.columns[col][row].is_occupied() {
+                    continue;
+                }
+                board.columns[col][row] = team.into();
+                if ai::completes_line(&board, col, row) {
+                    threats.push(Threat {
+                        column: col + 1,
+                        row: row + 1,
+                        playable: next == Some(row),
+                    });
+                }
+                board.columns[col][row] = CellValue::Empty;
+            }
+        }
+        threats
+    }
+
+    /// Every column the team can place in looking up to `depth` moves ahead, it must be the team's
+    /// turn
+    ///
+    /// Once the game is over there are no columns to place in and nobody has a forced win
+    pub(super) fn analyze(&self, team: Team, depth: u32) -> Analysis {
+        let depth = self.search_depth(depth);
+        let empty = self
+            .columns
+            .iter()
+            .flatten()
+            .filter(|cell| cell.is_empty())
+            .count();
+        let over = self.winner().is_some();
+
+        let columns: Vec<_> = (0..self.rules.width)
+            .filter(|&col| !over && self.columns[col].iter().any(|cell| cell.is_empty()))
+            .map(|col| {
+                let mut board = self.clone();
+                board
+                    .place(team, col)
+                    .expect("only columns with space in a game in progress are tried");
+                let (outcome, moves, score) = match board.winner() {
+                    Some(Some(_)) => (Outcome::Win, Some(1), None),
+                    Some(None) => (Outcome::Draw, Some(1), None),
+                    None => {
+                        let score = -ai::negamax(
+                            &mut board,
+                            team.opponent(),
+                            depth - 1,
+                            -ai::WIN * 2,
+                            ai::WIN * 2,
+                        );
+                        outcome(score, depth, empty)
+                    }
+                };
+                ColumnAnalysis {
+                    column: col + 1,
+                    outcome,
+                    moves,
+                    score,
+                    threats: board.threats(team).len(),
+                }
+            })
+            .collect();
+
+        let fastest_win = columns
+            .iter()
+            .filter(|column| column.outcome == Outcome::Win)
+            .min_by_key(|column| column.moves);
+        let forced_win = if let Some(win) = fastest_win {
+            Some(ForcedWin {
+                team,
+                moves: win
+                    .moves
+                    .expect("wins are always in a known number of moves"),
+                column: Some(win.column),
+            })
+        } else if !columns.is_empty()
+            && columns.iter().all(|column| column.outcome == Outcome::Loss)
+        {
+            // The longest the team can hold out for
+            columns
+                .iter()
+                .filter_map(|column| column.moves)
+                .max()
+                .map(|moves| ForcedWin {
+                    team: team.opponent(),
+                    moves,
+                    column: None,
+                })
+        } else {
+            None
+        };
+
+        Analysis {
+            team,
+            depth,
+            columns,
+            threats: Threats {
+                cookie: self.threats(Team::Cookie),
+                milk: self.threats(Team::Milk),
+            },
+            forced_win,
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use actix_web::{dev::Payload, error, web, FromRequest, HttpRequest};
+use anyhow::Context as _;
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+use tracing::error;
+use uuid::Uuid;
+
+use super::{
+    store::{self, SaveQueue, Snapshot},
+    Board,
+};
+
+/// Game used by the routes without a game id, it never expires
+pub const DEFAULT_GAME: Uuid = Uuid::nil();
+
+/// Games not used for this long are removed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Most games kept at once so creating games can't use up all the memory
+const MAX_GAMES: usize = 1_000;
+
+/// Board updates kept for watchers that fall behind, only the latest one matters
+const UPDATES_CAPACITY: usize = 16;
+
+/// The game a request is for, taken from the `id` path segment or [`DEFAULT_GAME`] without one
+#[derive(Debug, Clone, Copy)]
+pub struct GameId(pub Uuid);
+
+impl FromRequest for GameId {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match req.match_info().get("id") {
+            Some(id) => Uuid::try_parse(id)
+                .map(Self)
+                .map_err(error::ErrorBadRequest),
+            None => Ok(Self(DEFAULT_GAME)),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Game {
+    board: Board,
+    last_used: Instant,
+    /// Sends [`Board::update_message`] to everyone watching after every change
+    updates: broadcast::Sender<String>,
+}
+
+impl Game {
+    fn new(board: Board) -> Self {
+        Self {
+            board,
+            last_used: Instant::now(),
+            updates: broadcast::channel(UPDATES_CAPACITY).0,
+        }
+    }
+
+    /// A saved game that was last used `idle` ago
+    fn restored(board: Board, idle: Duration) -> Self {
+        let mut game = Self::new(board);
+        game.last_used = Instant::now().checked_sub(idle).unwrap_or(game.last_used);
+        game
+    }
+}
+
+/// Every game in progress, idle games are removed whenever games are accessed
+///
+/// Games are saved to Postgres after every change and those that weren't idle are picked up again
+/// after a restart
+#[derive(Debug)]
+pub struct Games {
+    games: HashMap<Uuid, Game>,
+    /// Saved by [`store::save_all`] so a slow save doesn't hold up the game
+    saves: Arc<SaveQueue>,
+}
+
+impl Games {
+    /// Must be called from within a Tokio runtime as saving is done by a spawned task
+    pub async fn load(pool: PgPool) -> web::Data<Mutex<Self>> {
+        let mut games = HashMap::from([(DEFAULT_GAME, Game::new(Board::new()))]);
+        match store::load_recent(&pool, IDLE_TIMEOUT, DEFAULT_GAME).await {
+            Ok(saved) => games.extend(
+                saved
+                    .into_iter()
+                    .map(|(id, board, idle)| (id, Game::restored(board, idle))),
+            ),
+            Err(e) => error!(?e, "failed to load saved games, starting without them"),
+        }
+        let saves = Arc::new(SaveQueue::default());
+        tokio::spawn(store::save_all(pool, saves.clone()));
+        web::Data::new(Mutex::new(Self { games, saves }))
+    }
+
+    fn save(&self, snapshot: Snapshot) {
+        self.saves.push(snapshot);
+    }
+
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        self.games.retain(|id, game| {
+            *id == DEFAULT_GAME || now.duration_since(game.last_used) < IDLE_TIMEOUT
+        });
+    }
+
+    /// Starts a new game with the board and returns its id
+    pub fn create(&mut self, board: Board) -> actix_web::Result<Uuid> {
+        self.remove_expired();
+        if self.games.len() >= MAX_GAMES {
+            return Err(error::ErrorServiceUnavailable("too many games in progress"));
+        }
+        let id = Uuid::new_v4();
+        self.save(Snapshot::new(id, &board, false));
+        self.games.insert(id, Game::new(board));
+        Ok(id)
+    }
+
+    /// Fails with 404 Not Found if the game doesn't exist or has expired
+    fn game_mut(&mut self, id: &GameId) -> actix_web::Result<&mut Game> {
+        self.remove_expired();
+        let game = self
+            .games
+            .get_mut(&id.0)
+            .context("game not found")
+            .map_err(error::ErrorNotFound)?;
+        game.last_used = Instant::now();
+        Ok(game)
+    }
+
+    pub fn board(&mut self, id: &GameId) -> actix_web::Result<&Board> {
+        Ok(&self.game_mut(id)?.board)
+    }
+
+    /// Changes the board, saving it and telling everyone watching about it unless the change
+    /// fails
+    pub fn update<T>(
+        &mut self,
+        id: &GameId,
+        change: impl FnOnce(&mut Board) -> actix_web::Result<T>,
+    ) -> actix_web::Result<T> {
+        let game = self.game_mut(id)?;
+        let result = change(&mut game.board)?;
+        let finished = game.board.record_result();
+        if game.updates.receiver_count() > 0 {
+            // Only fails if everyone stopped watching in the meantime
+            let _ = game.updates.send(game.board.update_message());
+        }
+        let snapshot = Snapshot::new(id.0, &game.board, finished);
+        self.save(snapshot);
+        Ok(result)
+    }
+
+    /// Board updates from now on and the current board to start with
+    pub fn watch(
+        &mut self,
+        id: &GameId,
+    ) -> actix_web::Result<(broadcast::Receiver<String>, String)> {
+        let game = self.game_mut(id)?;
+        Ok((game.updates.subscribe(), game.board.update_message()))
+    }
+}
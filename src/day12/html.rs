@@ -0,0 +1,102 @@
+use actix_web::error;
+use serde::Serialize;
+use std::sync::LazyLock;
+use tera::{Context, Tera};
+use tracing::info;
+
+use super::{
+    games::{GameId, DEFAULT_GAME},
+    Board, CellValue, Team,
+};
+
+fn templates() -> &'static Tera {
+    static LAZY_LOCK: LazyLock<Tera> = LazyLock::new(|| {
+        info!("Day 12 templates loaded");
+        let mut tera = Tera::default();
+        tera.add_raw_templates([
+            ("board.html", include_str!("../../templates/12/board.html")),
+            ("page.html", include_str!("../../templates/12/page.html")),
+        ])
+        .expect("templates are valid");
+        tera
+    });
+    &LAZY_LOCK
+}
+
+#[derive(Debug, Serialize)]
+struct ColumnView {
+    /// Bottom first
+    cells: Vec<&'static str>,
+    playable: bool,
+}
+
+/// What the templates need to show a board
+#[derive(Debug, Serialize)]
+struct BoardView {
+    /// Path the game's routes are under
+    base: String,
+    /// Team that clicking a column places for, cookie starts when either team may
+    team: &'static str,
+    columns: Vec<ColumnView>,
+    players: Option<String>,
+    status: String,
+    error: Option<String>,
+}
+
+impl BoardView {
+    fn new(game: &GameId, board: &Board, error: Option<String>) -> Self {
+        let base = if game.0 == DEFAULT_GAME {
+            "/12".to_string()
+        } else {
+            format!("/12/games/{}", game.0)
+        };
+        let game_over = board.winner().is_some();
+        let columns = board
+            .columns
+            .iter()
+            .map(|column| ColumnView {
+                cells: column.iter().map(CellValue::as_str).collect(),
+                playable: !game_over && column.iter().any(|cell| cell.is_empty()),
+            })
+            .collect();
+        let players = match (&board.players.cookie, &board.players.milk) {
+            (None, None) => None,
+            (cookie, milk) => Some(format!(
+                "{} {} vs {} {}",
+                Team::Cookie,
+                cookie.as_deref().unwrap_or("?"),
+                Team::Milk,
+                milk.as_deref().unwrap_or("?"),
+            )),
+        };
+        let status = board.winner_status().unwrap_or_else(|| match board.turn() {
+            Some(team) => format!("It is {team}'s turn."),
+            None => "Either team may start.".to_string(),
+        });
+        Self {
+            base,
+            team: board.turn().unwrap_or(Team::Cookie).name(),
+            columns,
+            players,
+            status,
+            error,
+        }
+    }
+}
+
+fn render(template: &str, view: &BoardView) -> actix_web::Result<String> {
+    let context = Context::from_serialize(view).map_err(error::ErrorInternalServerError)?;
+    templates()
+        .render(template, &context)
+        .map_err(error::ErrorInternalServerError)
+}
+
+/// The whole page with the board in it
+pub fn page(game: &GameId, board: &Board) -> actix_web::Result<String> {
+    render("page.html", &BoardView::new(game, board, None))
+}
+
+/// Just the board for htmx to swap in, with the error from the last action if it failed
+pub fn board(game: &GameId, board: &Board, error: Option<String>) -> actix_web::Result<String> {
+    render("board.html", &BoardView::new(game, board, error))
+}
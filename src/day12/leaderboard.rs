@@ -0,0 +1,226 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use super::Team;
+
+/// Rating everyone starts with
+const INITIAL_RATING: f64 = 1500.0;
+
+/// Most a rating can change by in one game
+const K_FACTOR: f64 = 32.0;
+
+/// A finished game from `connect_four_results`
+#[derive(Debug)]
+pub struct GameResult {
+    pub cookie_player: Option<String>,
+    pub milk_player: Option<String>,
+    /// `None` for a draw
+    pub winner: Option<Team>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    wins: u64,
+    losses: u64,
+    draws: u64,
+    rating: f64,
+}
+
+impl Default for Record {
+    fn default() -> Self {
+        Self {
+            wins: 0,
+            losses: 0,
+            draws: 0,
+            rating: INITIAL_RATING,
+        }
+    }
+}
+
+/// Records of everyone that played, by name
+#[derive(Debug, Default)]
+struct Records(HashMap<String, Record>);
+
+impl Records {
+    /// Adds a game between the two, updating their ratings with the Elo formula
+    fn add(&mut self, cookie: &str, milk: &str, winner: Option<Team>) {
+        let cookie_rating = self.0.entry(cookie.to_string()).or_default().rating;
+        let milk_rating = self.0.entry(milk.to_string()).or_default().rating;
+        let expected = 1.0 / (1.0 + 10f64.powf((milk_rating - cookie_rating) / 400.0));
+        let score = match winner {
+            Some(Team::Cookie) => 1.0,
+            Some(Team::Milk) => 0.0,
+            None => 0.5,
+        };
+        let change = K_FACTOR * (score - expected);
+
+        for (name, team, change) in [(cookie, Team::Cookie, change), (milk, Team::Milk, -change)] {
+            let record = self.0.get_mut(name).expect("added above");
+            match winner {
+                Some(winner) if winner == team => record.wins += 1,
+                Some(_) => record.losses += 1,
+                None => record.draws += 1,
+            }
+            record.rating += change;
+        }
+    }
+
+    /// Highest rating first
+    fn into_standings(self) -> Vec<Standing> {
+        let mut standings: Vec<_> = self
+            .0
+            .into_iter()
+            .map(|(name, record)| Standing::new(name, record))
+            .collect();
+        standings.sort_by(|a, b| b.rating.total_cmp(&a.rating).then(a.name.cmp(&b.name)));
+        standings
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Standing {
+    name: String,
+    games: u64,
+    wins: u64,
+    losses: u64,
+    draws: u64,
+    /// Wins out of all games, 0 without any games
+    win_rate: f64,
+    /// Rounded to one decimal place
+    rating: f64,
+}
+
+impl Standing {
+    fn new(name: String, record: Record) -> Self {
+        let games = record.wins + record.losses + record.draws;
+        Self {
+            name,
+            games,
+            wins: record.wins,
+            losses: record.losses,
+            draws: record.draws,
+            win_rate: if games == 0 {
+                0.0
+            } else {
+                record.wins as f64 / games as f64
+            },
+            rating: (record.rating * 10.0).round() / 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Leaderboard {
+    teams: Vec<Standing>,
+    /// Only games between two different named players count towards these
+    players: Vec<Standing>,
+}
+
+impl Leaderboard {
+    /// Results must be oldest first as ratings depend on the order games were played in
+    pub fn from_results(results: &[GameResult]) -> Self {
+        let mut teams = Records::default();
+        for team in [Team::Cookie, Team::Milk] {
+            teams.0.insert(team.name().to_string(), Record::default());
+        }
+        let mut players = Records::default();
+        for result in results {
+            teams.add(Team::Cookie.name(), Team::Milk.name(), result.winner);
+            if let (Some(cookie), Some(milk)) = (&result.cookie_player, &result.milk_player) {
+                if cookie != milk {
+                    players.add(cookie, milk, result.winner);
+                }
+            }
+        }
+        Self {
+            teams: teams.into_standings(),
+            players: players.into_standings(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(cookie: Option<&str>, milk: Option<&str>, winner: Option<Team>) -> GameResult {
+        GameResult {
+            cookie_player: cookie.map(str::to_string),
+            milk_player: milk.map(str::to_string),
+            winner,
+        }
+    }
+
+    fn standing<'a>(standings: &'a [Standing], name: &str) -> &'a Standing {
+        standings
+            .iter()
+            .find(|standing| standing.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn win_moves_ratings_by_half_of_k() {
+        let mut records = Records::default();
+        records.add("alice", "bob", Some(Team::Milk));
+        let (alice, bob) = (records.0["alice"], records.0["bob"]);
+        assert_eq!(alice.rating, INITIAL_RATING - K_FACTOR / 2.0);
+        assert_eq!(bob.rating, INITIAL_RATING + K_FACTOR / 2.0);
+        assert_eq!((alice.wins, alice.losses), (0, 1));
+        assert_eq!((bob.wins, bob.losses), (1, 0));
+
+        let standings = records.into_standings();
+        assert_eq!(standings[0].name, "bob");
+        assert_eq!(standings[0].rating, 1516.0);
+        assert_eq!(standings[1].rating, 1484.0);
+    }
+
+    #[test]
+    fn draw_between_equal_ratings_changes_nothing() {
+        let mut records = Records::default();
+        records.add("alice", "bob", None);
+        for name in ["alice", "bob"] {
+            let record = records.0[name];
+            assert_eq!(record.rating, INITIAL_RATING);
+            assert_eq!(record.draws, 1);
+        }
+    }
+
+    #[test]
+    fn win_rate_counts_draws_as_games() {
+        let leaderboard = Leaderboard::from_results(&[
+            result(None, None, Some(Team::Cookie)),
+            result(None, None, None),
+            result(None, None, Some(Team::Milk)),
+            result(None, None, Some(Team::Cookie)),
+        ]);
+        let cookie = standing(&leaderboard.teams, "cookie");
+        assert_eq!(
+            (cookie.games, cookie.wins, cookie.losses, cookie.draws),
+            (4, 2, 1, 1)
+        );
+        assert_eq!(cookie.win_rate, 0.5);
+        let milk = standing(&leaderboard.teams, "milk");
+        assert_eq!(milk.win_rate, 0.25);
+
+        let empty = Leaderboard::from_results(&[]);
+        assert_eq!(standing(&empty.teams, "cookie").win_rate, 0.0);
+    }
+
+    #[test]
+    fn players_are_ranked_separately_from_teams() {
+        let leaderboard = Leaderboard::from_results(&[
+            result(Some("alice"), Some("bob"), Some(Team::Cookie)),
+            result(Some("bob"), Some("alice"), Some(Team::Cookie)),
+            // Only count towards the teams
+            result(Some("alice"), None, Some(Team::Milk)),
+            result(Some("alice"), Some("alice"), Some(Team::Milk)),
+        ]);
+        let cookie = standing(&leaderboard.teams, "cookie");
+        assert_eq!((cookie.games, cookie.wins), (4, 2));
+        assert_eq!(leaderboard.players.len(), 2);
+        for name in ["alice", "bob"] {
+            let player = standing(&leaderboard.players, name);
+            assert_eq!((player.games, player.wins, player.losses), (2, 1, 1));
+        }
+    }
+}
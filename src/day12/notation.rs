@@ -0,0 +1,187 @@
+use anyhow::{bail, Context as _};
+use std::{fmt::Display, str::FromStr};
+
+use super::{Board, CellValue, Rules};
+
+const COLUMN_SEPARATOR: char = '/';
+
+/// The pieces on a board as text so positions can be shared
+///
+/// Columns from left to right separated by `/`, each from the bottom up with `c` for cookie, `m`
+/// for milk and `.` for empty, eg. `cm../c.../..../....`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notation(pub Vec<Vec<CellValue>>);
+
+impl CellValue {
+    fn notation(self) -> char {
+        match self {
+            CellValue::Empty => '.',
+            CellValue::Cookie => 'c',
+            CellValue::Milk => 'm',
+        }
+    }
+
+    fn from_notation(c: char) -> Option<Self> {
+        Some(match c {
+            '.' => CellValue::Empty,
+            'c' => CellValue::Cookie,
+            'm' => CellValue::Milk,
+            _ => return None,
+        })
+    }
+}
+
+impl From<&Board> for Notation {
+    fn from(board: &Board) -> Self {
+        Self(board.columns.clone())
+    }
+}
+
+impl Display for Notation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, column) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "{COLUMN_SEPARATOR}")?;
+            }
+            for cell in column {
+                write!(f, "{}", cell.notation())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Notation {
+    type Err = anyhow::Error;
+
+    /// Fails if the columns aren't all the same height or a piece is above an empty cell
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let columns = s
+            .trim()
+            .split(COLUMN_SEPARATOR)
+            .enumerate()
+            .map(|(i, column)| {
+                column
+                    .chars()
+                    .map(|c| {
+                        CellValue::from_notation(c)
+                            .with_context(|| format!("{c:?} in column {} is not a cell", i + 1))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let height = columns[0].len();
+        if columns.iter().any(|column| column.len() != height) {
+            bail!("every column must be the same height");
+        }
+        if !(1..=Rules::MAX_SIZE).contains(&columns.len())
+            || !(1..=Rules::MAX_SIZE).contains(&height)
+        {
+            bail!("width and height must be between 1 and {}", Rules::MAX_SIZE);
+        }
+        if let Some(i) = columns.iter().position(|column| {
+            column
+                .windows(2)
+                .any(|cells| cells[0].is_empty() && cells[1].is_occupied())
+        }) {
+            bail!("column {} has a piece above an empty cell", i + 1);
+        }
+        Ok(Self(columns))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::Team, *};
+
+    fn parse_error(s: &str) -> String {
+        s.parse::<Notation>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parse() {
+        let Notation(columns) = "cm../c.../..../....".parse().unwrap();
+        assert_eq!(columns.len(), 4);
+        assert_eq!(
+            columns[0],
+            [
+                CellValue::Cookie,
+                CellValue::Milk,
+                CellValue::Empty,
+                CellValue::Empty
+            ]
+        );
+        assert_eq!(columns[1][0], CellValue::Cookie);
+        assert!(columns[2..].iter().flatten().all(|cell| cell.is_empty()));
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        let notation: Notation = " c./..\n".parse().unwrap();
+        assert_eq!(notation.to_string(), "c./..");
+    }
+
+    #[test]
+    fn floating_piece() {
+        assert_eq!(
+            parse_error("c.../.c../..../...."),
+            "column 2 has a piece above an empty cell"
+        );
+    }
+
+    #[test]
+    fn ragged_columns() {
+        assert_eq!(
+            parse_error("..../.../..../...."),
+            "every column must be the same height"
+        );
+        assert_eq!(
+            parse_error("..../..../..../..../"),
+            "every column must be the same height"
+        );
+    }
+
+    #[test]
+    fn bad_char() {
+        assert_eq!(parse_error("..../..x./"), "'x' in column 2 is not a cell");
+        assert_eq!(parse_error("C..."), "'C' in column 1 is not a cell");
+    }
+
+    #[test]
+    fn empty_input() {
+        let error = "width and height must be between 1 and 16";
+        assert_eq!(parse_error(""), error);
+        assert_eq!(parse_error("  \n"), error);
+        assert_eq!(parse_error("/"), error);
+    }
+
+    #[test]
+    fn size_limit() {
+        let error = "width and height must be between 1 and 16";
+        let column = |height| ".".repeat(height);
+        assert!(vec![column(16); 16].join("/").parse::<Notation>().is_ok());
+        assert_eq!(parse_error(&vec![column(17); 16].join("/")), error);
+        assert_eq!(parse_error(&vec![column(16); 17].join("/")), error);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for position in [
+            "cm../c.../..../....",
+            "cmcmcm/mcmcmc/....../c...../m...../cc..../mmm...",
+            ".",
+            "m",
+        ] {
+            let notation: Notation = position.parse().unwrap();
+            assert_eq!(notation.to_string(), position);
+            assert_eq!(notation.to_string().parse::<Notation>().unwrap(), notation);
+        }
+
+        let mut board = Board::new();
+        board.place(Team::Milk, 2).unwrap();
+        let notation = Notation::from(&board);
+        assert_eq!(notation.to_string(), "..../..../m.../....");
+        assert_eq!(notation.to_string().parse::<Notation>().unwrap(), notation);
+    }
+}
@@ -0,0 +1,409 @@
+use actix_web::{error, rt::time::sleep};
+use anyhow::Context as _;
+use chrono::{DateTime, Utc};
+use sqlx::{types::Json, PgPool};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use super::{leaderboard::GameResult, Board, CellValue, Move, Players, Rules, Team};
+
+/// How long to wait before saving again after a save failed
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A result waiting to go on the leaderboard
+#[derive(Debug)]
+struct FinishedGame {
+    players: Players,
+    winner: Option<Team>,
+    at: DateTime<Utc>,
+}
+
+/// A game as it was after a change, waiting to be saved by [`save_all`]
+#[derive(Debug)]
+pub struct Snapshot {
+    id: Uuid,
+    rules: Rules,
+    /// `None` for an empty board
+    start: Option<Vec<Vec<CellValue>>>,
+    moves: Vec<Move>,
+    players: Players,
+    /// `None` while the game is in progress
+    outcome: Option<Option<Team>>,
+    /// Games finished since the last save, oldest first, kept even if the finishing move has
+    /// since been undone
+    results: Vec<FinishedGame>,
+    at: DateTime<Utc>,
+}
+
+impl Snapshot {
+    /// `record_result` if the change finished the game so the result goes on the leaderboard
+    pub fn new(id: Uuid, board: &Board, record_result: bool) -> Self {
+        let at = Utc::now();
+        let outcome = board.winner();
+        let results = match outcome {
+            Some(winner) if record_result => vec![FinishedGame {
+                players: board.players.clone(),
+                winner,
+                at,
+            }],
+            _ => vec![],
+        };
+        Self {
+            id,
+            rules: board.rules,
+            start: board
+                .start
+                .iter()
+                .flatten()
+                .any(|cell| cell.is_occupied())
+                .then(|| board.start.clone()),
+            moves: board.moves.clone(),
+            players: board.players.clone(),
+            outcome,
+            results,
+            at,
+        }
+    }
+}
+
+/// Snapshots waiting for [`save_all`], only the latest of each game is kept
+///
+/// Saving can fall behind (eg. while Postgres is slow) without snapshots piling up as the older
+/// ones would be overwritten straight away
+#[derive(Debug, Default)]
+pub struct SaveQueue {
+    pending: Mutex<HashMap<Uuid, Snapshot>>,
+    /// Snapshots replaced before they were saved, logged and reset by [`save_all`]
+    superseded: AtomicUsize,
+    ready: Notify,
+}
+
+impl SaveQueue {
+    /// Replaces the game's snapshot if it hasn't been saved yet, keeping its results
+    ///
+    /// Also used to retry a snapshot that failed to save, which only keeps its results if the game
+    /// has changed since
+    pub fn push(&self, mut snapshot: Snapshot) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(queued) = pending.remove(&snapshot.id) {
+            let (mut newer, older) = if queued.at > snapshot.at {
+                (queued, snapshot)
+            } else {
+                (snapshot, queued)
+            };
+            newer.results.splice(0..0, older.results);
+            snapshot = newer;
+            self.superseded.fetch_add(1, Ordering::Relaxed);
+        }
+        pending.insert(snapshot.id, snapshot);
+        self.ready.notify_one();
+    }
+
+    /// Waits until there is something to save
+    async fn take(&self) -> Vec<Snapshot> {
+        loop {
+            let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+            if !pending.is_empty() {
+                return pending.into_values().collect();
+            }
+            self.ready.notified().await;
+        }
+    }
+}
+
+/// A `connect_four_games` row
+struct GameRow {
+    id: Uuid,
+    width: i32,
+    height: i32,
+    connect: i32,
+    start: Option<Json<Vec<Vec<CellValue>>>>,
+    moves: Json<Vec<Move>>,
+    cookie_player: Option<String>,
+    milk_player: Option<String>,
+    updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<GameRow> for Board {
+    type Error = anyhow::Error;
+
+    fn try_from(row: GameRow) -> Result<Self, Self::Error> {
+        let rules = Rules {
+            width: row.width.try_into()?,
+            height: row.height.try_into()?,
+            connect: row.connect.try_into()?,
+        };
+        let mut board = Board::from_moves(rules, row.start.map(|start| start.0), &row.moves)?;
+        board.players = Players {
+            cookie: row.cookie_player,
+            milk: row.milk_player,
+        };
+        Ok(board)
+    }
+}
+
+/// Saves the latest snapshot of each game one at a time, forever
+pub async fn save_all(pool: PgPool, queue: Arc<SaveQueue>) {
+    loop {
+        let snapshots = queue.take().await;
+        let superseded = queue.superseded.swap(0, Ordering::Relaxed);
+        if superseded > 0 {
+            info!(
+                superseded,
+                "skipped snapshots replaced before they were saved"
+            );
+        }
+        let mut failed = false;
+        for snapshot in snapshots {
+            // Already logged, the game carries on in memory and is saved again later
+            if save(&pool, &snapshot).await.is_err() {
+                queue.push(snapshot);
+                failed = true;
+            }
+        }
+        if failed {
+            sleep(RETRY_DELAY).await;
+        }
+    }
+}
+
+/// The time a game finished is kept until it is undone or reset
+#[instrument(err(Debug), skip(pool))]
+async fn save(pool: &PgPool, snapshot: &Snapshot) -> sqlx::Result<()> {
+    let Snapshot {
+        id,
+        rules,
+        start,
+        moves,
+        players,
+        outcome,
+        results,
+        at,
+    } = snapshot;
+    let winner = outcome.flatten().map(Team::name);
+    let finished_at = outcome.is_some().then_some(at);
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "INSERT INTO connect_four_games
+            (id, width, height, connect, start, moves, cookie_player, milk_player, winner,
+            created_at, updated_at, finished_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $10, $11)
+        ON CONFLICT (id) DO UPDATE SET
+            width = EXCLUDED.width,
+            height = EXCLUDED.height,
+            connect = EXCLUDED.connect,
+            start = EXCLUDED.start,
+            moves = EXCLUDED.moves,
+            cookie_player = EXCLUDED.cookie_player,
+            milk_player = EXCLUDED.milk_player,
+            winner = EXCLUDED.winner,
+            updated_at = EXCLUDED.updated_at,
+            finished_at = CASE WHEN EXCLUDED.finished_at IS NOT NULL
+                THEN COALESCE(connect_four_games.finished_at, EXCLUDED.finished_at)
+            END;",
+        *id,
+        rules.width as i32,
+        rules.height as i32,
+        rules.connect as i32,
+        start.as_ref().map(Json) as _,
+        Json(moves) as _,
+        players.cookie.as_deref(),
+        players.milk.as_deref(),
+        winner,
+        *at,
+        finished_at
+    )
+    .execute(&mut *tx)
+    .await?;
+    for result in results {
+        sqlx::query!(
+            "INSERT INTO connect_four_results
+                (id, game_id, cookie_player, milk_player, winner, finished_at)
+            VALUES ($1, $2, $3, $4, $5, $6);",
+            Uuid::new_v4(),
+            *id,
+            result.players.cookie,
+            result.players.milk,
+            result.winner.map(Team::name),
+            result.at
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+/// Games changed within `within` (and the default game) with how long they have been idle
+///
+/// Games that can't be played back are skipped
+#[instrument(err(Debug), skip(pool))]
+pub async fn load_recent(
+    pool: &PgPool,
+    within: Duration,
+    default_game: Uuid,
+) -> sqlx::Result<Vec<(Uuid, Board, Duration)>> {
+    let now = Utc::now();
+    let rows = sqlx::query_as!(
+        GameRow,
+        r#"SELECT id, width, height, connect,
+            start as "start: Json<Vec<Vec<CellValue>>>", moves as "moves: Json<Vec<Move>>",
+            cookie_player, milk_player, updated_at
+        FROM connect_four_games
+        WHERE updated_at > $1 OR id = $2;"#,
+        now - within,
+        default_game
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let (id, idle) = (row.id, (now - row.updated_at).to_std().unwrap_or_default());
+            match Board::try_from(row) {
+                Ok(board) => Some((id, board, idle)),
+                Err(e) => {
+                    warn!(?e, %id, "skipping saved game");
+                    None
+                }
+            }
+        })
+        .collect())
+}
+
+/// `None` if the game was never saved
+#[instrument(err(Debug), skip(pool))]
+pub async fn load(pool: &PgPool, id: &Uuid) -> actix_web::Result<Option<Board>> {
+    let row = sqlx::query_as!(
+        GameRow,
+        r#"SELECT id, width, height, connect,
+            start as "start: Json<Vec<Vec<CellValue>>>", moves as "moves: Json<Vec<Move>>",
+            cookie_player, milk_player, updated_at
+        FROM connect_four_games
+        WHERE id = $1;"#,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    row.map(Board::try_from)
+        .transpose()
+        .context("saved game can't be played back")
+        .map_err(error::ErrorInternalServerError)
+}
+
+/// Every result on the leaderboard, oldest first
+#[instrument(err(Debug), skip(pool))]
+pub async fn results(pool: &PgPool) -> actix_web::Result<Vec<GameResult>> {
+    sqlx::query!(
+        "SELECT cookie_player, milk_player, winner FROM connect_four_results
+        ORDER BY finished_at, id;"
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(error::ErrorInternalServerError)?
+    .into_iter()
+    .map(|row| {
+        Ok(GameResult {
+            cookie_player: row.cookie_player,
+            milk_player: row.milk_player,
+            winner: row
+                .winner
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(error::ErrorInternalServerError)?,
+        })
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn queue_keeps_latest_snapshot_and_every_result() {
+        let queue = SaveQueue::default();
+        let (game, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut board = Board::new();
+        for _ in 0..3 {
+            board.place(Team::Cookie, 0).unwrap();
+            board.place(Team::Milk, 1).unwrap();
+        }
+        board.place(Team::Cookie, 0).unwrap();
+        queue.push(Snapshot::new(game, &board, true));
+        board.undo().unwrap();
+        queue.push(Snapshot::new(game, &board, false));
+        queue.push(Snapshot::new(other, &Board::new(), false));
+
+        let mut snapshots = queue.take().await;
+        snapshots.sort_by_key(|snapshot| snapshot.id != game);
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].moves.len(), 6);
+        assert_eq!(snapshots[0].outcome, None);
+        assert_eq!(snapshots[0].results.len(), 1);
+        assert_eq!(snapshots[0].results[0].winner, Some(Team::Cookie));
+        assert!(snapshots[1].results.is_empty());
+        assert_eq!(queue.superseded.load(Ordering::Relaxed), 1);
+        assert!(queue.pending.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn failed_snapshot_does_not_replace_a_newer_one() {
+        let queue = SaveQueue::default();
+        let game = Uuid::new_v4();
+        let mut board = Board::new();
+        board.place(Team::Cookie, 0).unwrap();
+        queue.push(Snapshot::new(game, &board, false));
+        let failed = queue.take().await;
+
+        board.place(Team::Milk, 1).unwrap();
+        queue.push(Snapshot::new(game, &board, false));
+        for snapshot in failed {
+            queue.push(snapshot);
+        }
+        let snapshots = queue.take().await;
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].moves.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn results_survive_a_failed_save() {
+        let queue = SaveQueue::default();
+        let game = Uuid::new_v4();
+        let mut board = Board::new();
+        for _ in 0..3 {
+            board.place(Team::Cookie, 0).unwrap();
+            board.place(Team::Milk, 1).unwrap();
+        }
+        board.place(Team::Cookie, 0).unwrap();
+        queue.push(Snapshot::new(game, &board, true));
+
+        // Saving fails and is retried, with nothing newer queued
+        for snapshot in queue.take().await {
+            queue.push(snapshot);
+        }
+        // Saving fails again, this time after the winning move was undone
+        let failed = queue.take().await;
+        board.undo().unwrap();
+        queue.push(Snapshot::new(game, &board, false));
+        for snapshot in failed {
+            queue.push(snapshot);
+        }
+
+        let snapshots = queue.take().await;
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].moves.len(), 6);
+        assert_eq!(snapshots[0].results.len(), 1);
+        assert_eq!(snapshots[0].results[0].winner, Some(Team::Cookie));
+    }
+}
@@ -0,0 +1,126 @@
+use actix_web::web;
+use actix_ws::{CloseCode, CloseReason, Message, MessageStream, Session};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{instrument, warn};
+
+use super::{
+    games::{GameId, Games},
+    Board, BoardJson, Team,
+};
+
+/// Sent to everyone watching a game whenever its board changes
+#[derive(Debug, Serialize)]
+struct BoardUpdate {
+    /// The emoji rendering
+    text: String,
+    board: BoardJson,
+}
+
+impl Board {
+    pub(super) fn update_message(&self) -> String {
+        serde_json::to_string(&BoardUpdate {
+            text: self.to_string(),
+            board: self.into(),
+        })
+        .expect("board is always serializable")
+    }
+}
+
+/// Sent only to the client whose command failed
+#[derive(Debug, Serialize)]
+struct CommandError {
+    error: String,
+}
+
+/// What clients can do to the board, sent as JSON text messages
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Command {
+    /// `column` starts at 1 like in the `place` route
+    Place { team: Team, column: usize },
+    /// Keeps the current rules
+    Reset,
+}
+
+impl Command {
+    fn apply(self, game: &GameId, games: &Mutex<Games>) -> actix_web::Result<()> {
+        games.lock().unwrap().update(game, |board| match self {
+            Command::Place { team, column } => {
+                let col = column
+                    .checked_sub(1)
+                    .ok_or_else(|| actix_web::error::ErrorBadRequest("columns start at 1"))?;
+                board.place(team, col)
+            }
+            Command::Reset => {
+                board.reset(None);
+                Ok(())
+            }
+        })
+    }
+}
+
+/// Runs until the client disconnects, sending them the board after every change and applying
+/// their commands
+#[instrument(skip_all, fields(game = %game.0))]
+pub async fn run(
+    mut session: Session,
+    mut messages: MessageStream,
+    mut updates: broadcast::Receiver<String>,
+    current: String,
+    game: GameId,
+    games: web::Data<Mutex<Games>>,
+) {
+    if session.text(current).await.is_err() {
+        return;
+    }
+    let reason = loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) => {
+                    if session.text(update).await.is_err() {
+                        return;
+                    }
+                }
+                // The updates still to come have the latest board
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => {
+                    break Some(CloseReason {
+                        code: CloseCode::Away,
+                        description: Some("game expired".to_string()),
+                    })
+                }
+            },
+            message = messages.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let result = serde_json::from_str::<Command>(&text)
+                        .map_err(actix_web::error::ErrorBadRequest)
+                        .and_then(|command| command.apply(&game, &games));
+                    if let Err(e) = result {
+                        let error = serde_json::to_string(&CommandError {
+                            error: e.to_string(),
+                        })
+                        .expect("error is always serializable");
+                        if session.text(error).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Some(Ok(Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(Message::Close(reason))) => break reason,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    warn!(?e, "websocket protocol error");
+                    break None;
+                }
+                None => break None,
+            },
+        }
+    };
+    let _ = session.close(reason).await;
+}
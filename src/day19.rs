@@ -1,133 +1,1018 @@
-use actix_web::{error, web, HttpResponse};
-use anyhow::Context;
-use chrono::Utc;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+    error,
+    http::header::{CacheControl, CacheDirective, ContentType, ETag, EntityTag},
+    middleware::from_fn,
+    web, HttpResponse, Resource,
+};
+use anyhow::{bail, Context};
+use auth::{require_scope, ApiKeys, Scope};
+use chrono::{Datelike as _, NaiveTime, Utc};
+use events::{QuoteEvent, QuoteEvents};
+use futures_util::stream;
+use rand::{distributions::Alphanumeric, thread_rng, Rng as _};
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use store::{Precondition, QuoteFilter, QuoteStore};
 use tracing::instrument;
 use uuid::Uuid;
+use validation::{normalise_text, reject_duplicate};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Quote {
+mod auth;
+mod events;
+mod store;
+mod validation;
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Quote {
     id: Uuid,
     author: String,
     quote: String,
     created_at: chrono::DateTime<Utc>,
     version: i32,
+    /// Sorted by name
+    #[sqlx(json)]
+    tags: Vec<String>,
+}
+
+impl Quote {
+    /// The version is used as the entity tag so clients can detect concurrent edits
+    fn etag(&self) -> ETag {
+        ETag(EntityTag::new_strong(self.version.to_string()))
+    }
+}
+
+/// A quote that was removed and can still be restored
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TrashedQuote {
+    id: Uuid,
+    author: String,
+    quote: String,
+    created_at: chrono::DateTime<Utc>,
+    version: i32,
+    deleted_at: chrono::DateTime<Utc>,
+}
+
+/// A single revision of a quote as it was saved by `draft`, `undo` or `revert`
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct QuoteVersion {
+    version: i32,
+    author: String,
+    quote: String,
+    created_at: chrono::DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct DraftQuote {
+pub struct DraftQuote {
     author: String,
     quote: String,
+    /// When updating, `None` leaves the tags as they were
+    #[serde(default)]
+    tags: Option<Vec<String>>,
 }
 
-#[instrument(ret, err(Debug), skip(pool))]
-async fn reset(pool: web::Data<PgPool>) -> actix_web::Result<HttpResponse> {
-    let pool: &PgPool = &pool;
-    sqlx::query!("TRUNCATE quotes;")
-        .execute(pool)
-        .await
-        .map_err(error::ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().finish())
+impl DraftQuote {
+    /// Tags trimmed, lower cased, sorted and without duplicates
+    fn tags(&self) -> Option<Vec<String>> {
+        let mut tags: Vec<String> = self
+            .tags
+            .as_ref()?
+            .iter()
+            .map(|tag| normalise_tag(tag))
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        tags.sort();
+        tags.dedup();
+        Some(tags)
+    }
+}
+
+fn normalise_tag(tag: &str) -> String {
+    normalise_text(tag).to_lowercase()
+}
+
+/// Summary of an author's quotes that are not in the trash
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AuthorStats {
+    author: String,
+    quotes: i64,
+    first_created_at: chrono::DateTime<Utc>,
+    last_created_at: chrono::DateTime<Utc>,
+    /// Every version after the first is counted as an edit
+    edits: i64,
+}
+
+/// Marks the start of a search term in [`SearchResult::snippet`]
+const HIGHLIGHT_START: &str = "**";
+/// Marks the end of a search term in [`SearchResult::snippet`]
+const HIGHLIGHT_STOP: &str = "**";
+
+/// A quote matching a search, best matches have the highest rank
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    quote: Quote,
+    rank: f32,
+    /// Part of the quote with the matching words between [`HIGHLIGHT_START`] and
+    /// [`HIGHLIGHT_STOP`]
+    snippet: String,
+}
+
+/// Number of quotes (not in the trash) that have the tag
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TagCount {
+    tag: String,
+    count: i64,
+}
+
+/// CSV has no lists so tags are joined into one field using this separator
+const CSV_TAG_SEPARATOR: char = ';';
+
+#[derive(Debug, Serialize)]
+struct CsvQuote<'a> {
+    id: Uuid,
+    author: &'a str,
+    quote: &'a str,
+    created_at: chrono::DateTime<Utc>,
+    version: i32,
+    tags: String,
+}
+
+impl<'a> From<&'a Quote> for CsvQuote<'a> {
+    fn from(quote: &'a Quote) -> Self {
+        Self {
+            id: quote.id,
+            author: &quote.author,
+            quote: &quote.quote,
+            created_at: quote.created_at,
+            version: quote.version,
+            tags: quote.tags.join(&CSV_TAG_SEPARATOR.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CsvDraftQuote {
+    author: String,
+    quote: String,
+    tags: Option<String>,
+}
+
+impl From<CsvDraftQuote> for DraftQuote {
+    fn from(value: CsvDraftQuote) -> Self {
+        Self {
+            author: value.author,
+            quote: value.quote,
+            tags: value
+                .tags
+                .map(|tags| tags.split(CSV_TAG_SEPARATOR).map(str::to_string).collect()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ImportError {
+    line: u64,
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ImportReport {
+    imported: usize,
+    errors: Vec<ImportError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyRequest {
+    name: String,
+    scope: Scope,
+}
+
+#[derive(Debug, Deserialize)]
+struct RandomQuery {
+    author: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
 }
 
-#[instrument(ret, err(Debug), skip(pool))]
-async fn get_quote(pool: &PgPool, id: &Uuid) -> actix_web::Result<Quote> {
-    sqlx::query_as!(
-        Quote,
-        "SELECT id, author, quote, created_at, version FROM quotes where id = $1;",
-        id
-    )
-    .fetch_optional(pool)
-    .await
-    .map_err(error::ErrorInternalServerError)?
-    .context("id not found")
-    .map_err(error::ErrorNotFound)
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    format: Option<String>,
+}
+
+/// Formats supported for bulk import and export of quotes
+#[derive(Debug, Clone, Copy)]
+enum QuoteFormat {
+    JsonLines,
+    Csv,
+}
+
+impl FromStr for QuoteFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ndjson" | "jsonl" => Ok(Self::JsonLines),
+            "csv" => Ok(Self::Csv),
+            other => bail!("{other:?} is not a supported format"),
+        }
+    }
 }
 
-#[instrument(ret, err(Debug), skip(pool))]
-async fn cite(path: web::Path<String>, pool: web::Data<PgPool>) -> actix_web::Result<HttpResponse> {
-    let pool: &PgPool = &pool;
+impl QuoteFormat {
+    fn from_content_type(content_type: &ContentType) -> Option<Self> {
+        match content_type.0.essence_str() {
+            "application/x-ndjson" | "application/jsonl" | "application/jsonlines" => {
+                Some(Self::JsonLines)
+            }
+            "text/csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            QuoteFormat::JsonLines => "application/x-ndjson",
+            QuoteFormat::Csv => "text/csv",
+        }
+    }
+
+    /// Parses every record along with its line, collecting the errors instead of stopping at the
+    /// first one
+    fn parse(&self, body: &str) -> (Vec<(u64, DraftQuote)>, Vec<ImportError>) {
+        let mut drafts = vec![];
+        let mut errors = vec![];
+        match self {
+            QuoteFormat::JsonLines => {
+                for (line, text) in (1..).zip(body.lines()) {
+                    if text.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str(text) {
+                        Ok(draft) => drafts.push((line, draft)),
+                        Err(e) => errors.push(ImportError {
+                            line,
+                            error: e.to_string(),
+                        }),
+                    }
+                }
+            }
+            QuoteFormat::Csv => {
+                let mut reader = csv::Reader::from_reader(body.as_bytes());
+                let headers = match reader.headers() {
+                    Ok(headers) => headers.clone(),
+                    Err(e) => {
+                        errors.push(ImportError {
+                            line: 1,
+                            error: e.to_string(),
+                        });
+                        return (drafts, errors);
+                    }
+                };
+                for record in reader.records() {
+                    let parsed = record.and_then(|record| {
+                        let line = record.position().map(|p| p.line()).unwrap_or_default();
+                        record
+                            .deserialize(Some(&headers))
+                            .map(|draft| (line, CsvDraftQuote::into(draft)))
+                    });
+                    match parsed {
+                        Ok(draft) => drafts.push(draft),
+                        Err(e) => errors.push(ImportError {
+                            line: e.position().map(|p| p.line()).unwrap_or_default(),
+                            error: e.to_string(),
+                        }),
+                    }
+                }
+            }
+        }
+        (drafts, errors)
+    }
+
+    fn encode(&self, quotes: &[Quote], with_header: bool) -> anyhow::Result<Vec<u8>> {
+        let mut result = vec![];
+        match self {
+            QuoteFormat::JsonLines => {
+                for quote in quotes {
+                    serde_json::to_writer(&mut result, quote)?;
+                    result.push(b'\n');
+                }
+            }
+            QuoteFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(with_header)
+                    .from_writer(&mut result);
+                for quote in quotes {
+                    writer.serialize(CsvQuote::from(quote))?;
+                }
+                writer.flush()?;
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Position reached by an export, the next chunk starts after the last quote sent
+#[derive(Debug)]
+struct ExportCursor {
+    store: Arc<dyn QuoteStore>,
+    format: QuoteFormat,
+    after: Option<(chrono::DateTime<Utc>, Uuid)>,
+}
+
+#[derive(Debug, Serialize)]
+struct QuotePage {
+    quotes: Vec<Quote>,
+    page: i64,
+    next_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    token: Option<String>,
+    author: Option<String>,
+    q: Option<String>,
+    tag: Option<String>,
+}
+
+/// Where a listing should continue from, looked up using the token given to the client
+#[derive(Debug, Clone)]
+struct PageCursor {
+    page: i64,
+    /// The last quote on the previous page (`created_at`, `id`), `None` on the first page
+    after: Option<(chrono::DateTime<Utc>, Uuid)>,
+    filter: QuoteFilter,
+}
+
+/// Cursors handed out to clients, they expire and the oldest are dropped when there are too many
+/// so paging can't use up all the memory
+#[derive(Debug, Default)]
+pub struct PageTokens {
+    cursors: HashMap<String, PageCursor>,
+    /// Tokens oldest first, every token lives as long so this is also the order they expire in
+    issued: VecDeque<(Instant, String)>,
+}
+
+impl PageTokens {
+    const TOKEN_LEN: usize = 16;
+    const TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+    const MAX_TOKENS: usize = 10_000;
+
+    fn new_wrapped() -> web::Data<Mutex<Self>> {
+        web::Data::new(Mutex::new(Self::default()))
+    }
+
+    /// Drops expired tokens, and the oldest ones until there is room for `room` more
+    fn remove_old(&mut self, room: usize) {
+        let now = Instant::now();
+        while let Some((issued_at, token)) = self.issued.front() {
+            if now.duration_since(*issued_at) < Self::TOKEN_TTL
+                && self.issued.len() + room <= Self::MAX_TOKENS
+            {
+                break;
+            }
+            self.cursors.remove(token);
+            self.issued.pop_front();
+        }
+    }
+
+    fn get(&mut self, token: &str) -> Option<PageCursor> {
+        self.remove_old(0);
+        self.cursors.get(token).cloned()
+    }
+
+    /// Stores the cursor and returns the token that can be used to retrieve it
+    fn issue(&mut self, cursor: PageCursor) -> String {
+        self.remove_old(1);
+        let token: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(Self::TOKEN_LEN)
+            .map(char::from)
+            .collect();
+        self.cursors.insert(token.clone(), cursor);
+        self.issued.push_back((Instant::now(), token.clone()));
+        token
+    }
+}
+
+#[instrument(ret, err(Debug), skip(store, events))]
+async fn reset(
+    store: web::Data<dyn QuoteStore>,
+    events: web::Data<QuoteEvents>,
+) -> actix_web::Result<HttpResponse> {
+    store.truncate().await?;
+    events.publish(QuoteEvent::Reset).await;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[instrument(ret, err(Debug), skip(store))]
+async fn cite(
+    path: web::Path<String>,
+    store: web::Data<dyn QuoteStore>,
+) -> actix_web::Result<HttpResponse> {
     let id = Uuid::try_parse(&path.into_inner()).map_err(error::ErrorBadRequest)?;
-    let quote = get_quote(pool, &id).await?;
-    Ok(HttpResponse::Ok().json(quote))
+    let quote = store.get(&id).await?;
+    Ok(HttpResponse::Ok().insert_header(quote.etag()).json(quote))
 }
 
-#[instrument(ret, err(Debug), skip(pool))]
+#[instrument(ret, err(Debug), skip(store, events))]
 async fn remove(
     path: web::Path<String>,
-    pool: web::Data<PgPool>,
+    precondition: Precondition,
+    store: web::Data<dyn QuoteStore>,
+    events: web::Data<QuoteEvents>,
 ) -> actix_web::Result<HttpResponse> {
-    let pool: &PgPool = &pool;
     let id = Uuid::try_parse(&path.into_inner()).map_err(error::ErrorBadRequest)?;
-    let quote = get_quote(pool, &id).await?;
-    sqlx::query!("DELETE FROM quotes WHERE id = $1", id)
-        .execute(pool)
-        .await
-        .map_err(error::ErrorInternalServerError)?;
+    let quote = store.delete(&id, &precondition).await?;
+    events.publish(QuoteEvent::Deleted { id }).await;
     Ok(HttpResponse::Ok().json(quote))
 }
 
-#[instrument(ret, err(Debug), skip(pool))]
+#[instrument(ret, err(Debug), skip(store, events))]
 async fn undo(
     web::Json(draft): web::Json<DraftQuote>,
     path: web::Path<String>,
-    pool: web::Data<PgPool>,
+    precondition: Precondition,
+    store: web::Data<dyn QuoteStore>,
+    events: web::Data<QuoteEvents>,
 ) -> actix_web::Result<HttpResponse> {
-    let pool: &PgPool = &pool;
     let id = Uuid::try_parse(&path.into_inner()).map_err(error::ErrorBadRequest)?;
-    let query_result = sqlx::query!(
-        "UPDATE quotes 
-        SET
-        author = $1, 
-        quote = $2,
-        version = version + 1
-        WHERE id = $3;",
-        draft.author,
-        draft.quote,
-        id
-    )
-    .execute(pool)
-    .await
-    .map_err(error::ErrorInternalServerError)?;
-    if query_result.rows_affected() != 1 {
-        return Err(error::ErrorNotFound(format!(
-            "Expected 1 row to match but found: {}",
-            query_result.rows_affected()
-        )));
-    }
-    let quote = get_quote(pool, &id).await?;
-    Ok(HttpResponse::Ok().json(quote))
+    let draft = draft.validated(store.as_ref(), Some(&id)).await?;
+    let quote = store.update(&id, &draft, &precondition).await?;
+    events
+        .publish(QuoteEvent::Updated {
+            quote: quote.clone(),
+        })
+        .await;
+    Ok(HttpResponse::Ok().insert_header(quote.etag()).json(quote))
+}
+
+/// Rejected with 422 if the old version duplicates another quote
+#[instrument(ret, err(Debug), skip(store, events))]
+async fn revert(
+    path: web::Path<(String, String)>,
+    store: web::Data<dyn QuoteStore>,
+    events: web::Data<QuoteEvents>,
+) -> actix_web::Result<HttpResponse> {
+    let (id, version) = path.into_inner();
+    let id = Uuid::try_parse(&id).map_err(error::ErrorBadRequest)?;
+    let version: i32 = version.parse().map_err(error::ErrorBadRequest)?;
+    let old = store
+        .history(&id)
+        .await?
+        .into_iter()
+        .find(|old| old.version == version)
+        .context("version not found")
+        .map_err(error::ErrorNotFound)?;
+    reject_duplicate(store.as_ref(), &old.author, &old.quote, Some(&id)).await?;
+    let quote = store.revert(&id, version).await?;
+    events
+        .publish(QuoteEvent::Updated {
+            quote: quote.clone(),
+        })
+        .await;
+    Ok(HttpResponse::Ok().insert_header(quote.etag()).json(quote))
 }
 
-#[instrument(ret, err(Debug), skip(pool))]
+#[instrument(ret, err(Debug), skip(store))]
+async fn history(
+    path: web::Path<String>,
+    store: web::Data<dyn QuoteStore>,
+) -> actix_web::Result<HttpResponse> {
+    let id = Uuid::try_parse(&path.into_inner()).map_err(error::ErrorBadRequest)?;
+    let versions = store.history(&id).await?;
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+#[instrument(ret, err(Debug), skip(store, events))]
 async fn draft(
     web::Json(draft): web::Json<DraftQuote>,
-    pool: web::Data<PgPool>,
-) -> actix_web::Result<HttpResponse> {
-    let pool: &PgPool = &pool;
-    let id = Uuid::new_v4();
-    let quote = sqlx::query_as!(
-        Quote,
-        "INSERT INTO quotes 
-        (id, author, quote) 
-        values ($1, $2, $3) 
-        RETURNING id, author, quote, created_at, version;",
-        id,
-        draft.author,
-        draft.quote
-    )
-    .fetch_one(pool)
-    .await
-    .map_err(error::ErrorInternalServerError)?;
-    Ok(HttpResponse::Created().json(quote))
+    store: web::Data<dyn QuoteStore>,
+    events: web::Data<QuoteEvents>,
+) -> actix_web::Result<HttpResponse> {
+    let draft = draft.validated(store.as_ref(), None).await?;
+    let quote = store.insert(&draft).await?;
+    events
+        .publish(QuoteEvent::Created {
+            quote: quote.clone(),
+        })
+        .await;
+    Ok(HttpResponse::Created()
+        .insert_header(quote.etag())
+        .json(quote))
+}
+
+#[instrument(ret, err(Debug), skip(store, events, body))]
+async fn import(
+    body: String,
+    content_type: Option<web::Header<ContentType>>,
+    store: web::Data<dyn QuoteStore>,
+    events: web::Data<QuoteEvents>,
+) -> actix_web::Result<HttpResponse> {
+    let format = content_type
+        .and_then(|web::Header(content_type)| QuoteFormat::from_content_type(&content_type))
+        .context("expected a content type of application/x-ndjson or text/csv")
+        .map_err(error::ErrorUnsupportedMediaType)?;
+    let (drafts, errors) = format.parse(&body);
+    if !errors.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(ImportReport {
+            imported: 0,
+            errors,
+        }));
+    }
+
+    let mut valid = Vec::with_capacity(drafts.len());
+    let mut errors = vec![];
+    let mut seen = HashSet::new();
+    for (line, draft) in drafts {
+        let draft = match draft.normalised() {
+            Ok(draft) => draft,
+            Err(e) => {
+                errors.push(ImportError {
+                    line,
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
+        if !seen.insert((draft.author.clone(), draft.quote.clone())) {
+            errors.push(ImportError {
+                line,
+                error: "quote: duplicates an earlier line by the same author".to_string(),
+            });
+        } else if let Some(id) = store
+            .find_duplicate(&draft.author, &draft.quote, None)
+            .await?
+        {
+            errors.push(ImportError {
+                line,
+                error: format!("quote: duplicates quote {id} by the same author"),
+            });
+        }
+        valid.push(draft);
+    }
+    if !errors.is_empty() {
+        return Ok(HttpResponse::UnprocessableEntity().json(ImportReport {
+            imported: 0,
+            errors,
+        }));
+    }
+
+    let quotes = store.insert_all(&valid).await?;
+    for quote in &quotes {
+        events
+            .publish(QuoteEvent::Created {
+                quote: quote.clone(),
+            })
+            .await;
+    }
+    Ok(HttpResponse::Created().json(ImportReport {
+        imported: quotes.len(),
+        errors,
+    }))
+}
+
+#[instrument(ret, err(Debug), skip(store))]
+async fn export(
+    web::Query(query): web::Query<ExportQuery>,
+    store: web::Data<dyn QuoteStore>,
+) -> actix_web::Result<HttpResponse> {
+    const CHUNK_SIZE: i64 = 100;
+    let format: QuoteFormat = query
+        .format
+        .as_deref()
+        .unwrap_or("ndjson")
+        .parse()
+        .map_err(error::ErrorBadRequest)?;
+    let cursor = ExportCursor {
+        store: store.into_inner(),
+        format,
+        after: None,
+    };
+
+    // Quotes are read a chunk at a time so the whole table never needs to be held in memory
+    let chunks = stream::try_unfold(Some(cursor), |cursor| async move {
+        let Some(cursor) = cursor else {
+            return Ok(None);
+        };
+        let quotes = cursor
+            .store
+            .list_after(&QuoteFilter::default(), cursor.after, CHUNK_SIZE)
+            .await?;
+        let bytes = cursor
+            .format
+            .encode(&quotes, cursor.after.is_none())
+            .map_err(error::ErrorInternalServerError)?;
+        let next = match quotes.last() {
+            Some(last) if quotes.len() as i64 == CHUNK_SIZE => Some(ExportCursor {
+                after: Some((last.created_at, last.id)),
+                ..cursor
+            }),
+            _ => None,
+        };
+        Ok::<_, actix_web::Error>(Some((web::Bytes::from(bytes), next)))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(chunks))
+}
+
+#[instrument(ret, err(Debug), skip(store, tokens))]
+async fn list(
+    web::Query(query): web::Query<ListQuery>,
+    store: web::Data<dyn QuoteStore>,
+    tokens: web::Data<Mutex<PageTokens>>,
+) -> actix_web::Result<HttpResponse> {
+    const PAGE_SIZE: i64 = 3;
+    let cursor = match query.token {
+        Some(token) => tokens
+            .lock()
+            .unwrap()
+            .get(&token)
+            .context("unknown or expired page token")
+            .map_err(error::ErrorBadRequest)?,
+        None => PageCursor {
+            page: 1,
+            after: None,
+            filter: QuoteFilter {
+                author: query.author,
+                q: query.q.filter(|q| !q.trim().is_empty()),
+                tag: query.tag.as_deref().map(normalise_tag),
+            },
+        },
+    };
+
+    // Fetch one extra row to find out if there is another page after this one
+    let mut quotes = store
+        .list_after(&cursor.filter, cursor.after, PAGE_SIZE + 1)
+        .await?;
+
+    let page = cursor.page;
+    let next_token = if quotes.len() as i64 > PAGE_SIZE {
+        quotes.truncate(PAGE_SIZE as usize);
+        let last = quotes.last().expect("there is more than a page of quotes");
+        Some(tokens.lock().unwrap().issue(PageCursor {
+            page: page + 1,
+            after: Some((last.created_at, last.id)),
+            ..cursor
+        }))
+    } else {
+        None
+    };
+    Ok(HttpResponse::Ok().json(QuotePage {
+        quotes,
+        page,
+        next_token,
+    }))
+}
+
+/// Returns the quote at `index` when quotes matching the filter are ordered by creation
+async fn nth_quote(
+    store: &dyn QuoteStore,
+    filter: &QuoteFilter,
+    index: i64,
+) -> actix_web::Result<Quote> {
+    store
+        .list(filter, index, 1)
+        .await?
+        .pop()
+        .context("no quotes found")
+        .map_err(error::ErrorNotFound)
+}
+
+#[instrument(ret, err(Debug), skip(store))]
+async fn random(
+    web::Query(query): web::Query<RandomQuery>,
+    store: web::Data<dyn QuoteStore>,
+) -> actix_web::Result<HttpResponse> {
+    let filter = QuoteFilter {
+        author: query.author,
+        ..Default::default()
+    };
+    let count = store.count(&filter).await?;
+    if count == 0 {
+        return Err(error::ErrorNotFound("no quotes found"));
+    }
+    let quote = nth_quote(&**store, &filter, thread_rng().gen_range(0..count)).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .json(quote))
+}
+
+/// The same quote is picked for everyone until midnight UTC (as long as the quotes don't change)
+#[instrument(ret, err(Debug), skip(store))]
+async fn daily(store: web::Data<dyn QuoteStore>) -> actix_web::Result<HttpResponse> {
+    let now = Utc::now();
+    let today = now.date_naive();
+    let filter = QuoteFilter::default();
+    let count = store.count(&filter).await?;
+    if count == 0 {
+        return Err(error::ErrorNotFound("no quotes found"));
+    }
+
+    // Scramble the day number so consecutive days don't just step through the quotes in order
+    let day = today.num_days_from_ce() as u64;
+    let index = day.wrapping_mul(0x9E37_79B9_7F4A_7C15) % count as u64;
+    let quote = nth_quote(&**store, &filter, index as i64).await?;
+
+    let midnight = today
+        .succ_opt()
+        .context("no date after today")
+        .map_err(error::ErrorInternalServerError)?
+        .and_time(NaiveTime::MIN)
+        .and_utc();
+    let max_age = (midnight - now).num_seconds().max(0) as u32;
+    Ok(HttpResponse::Ok()
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(max_age),
+        ]))
+        .json(quote))
+}
+
+#[instrument(ret, err(Debug), skip(store))]
+async fn tags(store: web::Data<dyn QuoteStore>) -> actix_web::Result<HttpResponse> {
+    let tags = store.tags().await?;
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+/// Results returned by `search` unless a limit is given
+const SEARCH_LIMIT: i64 = 10;
+/// Most results `search` returns no matter the limit asked for
+const SEARCH_LIMIT_MAX: i64 = 100;
+
+#[instrument(ret, err(Debug), skip(store))]
+async fn search(
+    web::Query(query): web::Query<SearchQuery>,
+    store: web::Data<dyn QuoteStore>,
+) -> actix_web::Result<HttpResponse> {
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Err(error::ErrorBadRequest("q must not be empty"));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(SEARCH_LIMIT)
+        .clamp(1, SEARCH_LIMIT_MAX);
+    let results = store.search(q, limit).await?;
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[instrument(ret, err(Debug), skip(store))]
+async fn authors(store: web::Data<dyn QuoteStore>) -> actix_web::Result<HttpResponse> {
+    let authors = store.authors().await?;
+    Ok(HttpResponse::Ok().json(authors))
+}
+
+#[instrument(ret, err(Debug), skip(store))]
+async fn author_quotes(
+    path: web::Path<String>,
+    store: web::Data<dyn QuoteStore>,
+) -> actix_web::Result<HttpResponse> {
+    let filter = QuoteFilter {
+        author: Some(path.into_inner()),
+        ..Default::default()
+    };
+    let quotes = store.list(&filter, 0, i64::MAX).await?;
+    if quotes.is_empty() {
+        return Err(error::ErrorNotFound("no quotes found for author"));
+    }
+    Ok(HttpResponse::Ok().json(quotes))
+}
+
+#[instrument(ret, err(Debug), skip(store))]
+async fn trash(store: web::Data<dyn QuoteStore>) -> actix_web::Result<HttpResponse> {
+    let quotes = store.trash().await?;
+    Ok(HttpResponse::Ok().json(quotes))
+}
+
+/// Rejected with 422 if a quote with the same author and text was added since it was removed
+#[instrument(ret, err(Debug), skip(store, events))]
+async fn restore(
+    path: web::Path<String>,
+    store: web::Data<dyn QuoteStore>,
+    events: web::Data<QuoteEvents>,
+) -> actix_web::Result<HttpResponse> {
+    let id = Uuid::try_parse(&path.into_inner()).map_err(error::ErrorBadRequest)?;
+    let trashed = store
+        .trash()
+        .await?
+        .into_iter()
+        .find(|trashed| trashed.id == id)
+        .context("id not found in trash")
+        .map_err(error::ErrorNotFound)?;
+    reject_duplicate(store.as_ref(), &trashed.author, &trashed.quote, Some(&id)).await?;
+    let quote = store.restore(&id).await?;
+    events
+        .publish(QuoteEvent::Created {
+            quote: quote.clone(),
+        })
+        .await;
+    Ok(HttpResponse::Ok().insert_header(quote.etag()).json(quote))
+}
+
+#[instrument(ret, err(Debug), skip(keys))]
+async fn list_keys(keys: web::Data<ApiKeys>) -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(keys.list().await?))
+}
+
+/// The response is the only time the key itself is shown
+#[instrument(err(Debug), skip(keys))]
+async fn create_key(
+    web::Json(request): web::Json<KeyRequest>,
+    keys: web::Data<ApiKeys>,
+) -> actix_web::Result<HttpResponse> {
+    let name = normalise_text(&request.name);
+    if name.is_empty() {
+        return Err(error::ErrorBadRequest("name must not be empty"));
+    }
+    let key = keys.create(&name, request.scope).await?;
+    Ok(HttpResponse::Created().json(key))
+}
+
+#[instrument(ret, err(Debug), skip(keys))]
+async fn revoke_key(
+    path: web::Path<String>,
+    keys: web::Data<ApiKeys>,
+) -> actix_web::Result<HttpResponse> {
+    let id = Uuid::try_parse(&path.into_inner()).map_err(error::ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(keys.revoke(&id).await?))
+}
+
+/// Streams changes to the quotes as Server-Sent Events until the client disconnects
+#[instrument(skip(events))]
+async fn events(events: web::Data<QuoteEvents>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(events.subscribe())
+}
+
+/// Largest body accepted by `import` in bytes
+const IMPORT_LIMIT: usize = 16 * 1024 * 1024;
+
+/// A resource that needs an API key with at least `scope`, or that anyone can use if `None`
+///
+/// Every route is registered through this so each one states who may use it
+fn resource(
+    path: &str,
+    scope: Option<Scope>,
+) -> Resource<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    web::resource(path).wrap(from_fn(move |req, next| require_scope(scope, req, next)))
 }
 
 pub(crate) fn scope() -> actix_web::Scope {
     web::scope("/19")
-        .route("/reset", web::post().to(reset))
-        .route("/cite/{id}", web::get().to(cite))
-        .route("/remove/{id}", web::delete().to(remove))
-        .route("/undo/{id}", web::put().to(undo))
-        .route("/draft", web::post().to(draft))
+        .service(resource("/reset", Some(Scope::Admin)).route(web::post().to(reset)))
+        .service(resource("/cite/{id}", None).route(web::get().to(cite)))
+        .service(resource("/remove/{id}", Some(Scope::Write)).route(web::delete().to(remove)))
+        .service(resource("/undo/{id}", Some(Scope::Write)).route(web::put().to(undo)))
+        .service(resource("/draft", Some(Scope::Write)).route(web::post().to(draft)))
+        .service(resource("/list", None).route(web::get().to(list)))
+        .service(resource("/history/{id}", None).route(web::get().to(history)))
+        .service(
+            resource("/revert/{id}/{version}", Some(Scope::Write)).route(web::post().to(revert)),
+        )
+        .service(resource("/trash", Some(Scope::Read)).route(web::get().to(trash)))
+        .service(resource("/restore/{id}", Some(Scope::Write)).route(web::post().to(restore)))
+        .service(
+            resource("/import", Some(Scope::Write))
+                .app_data(web::PayloadConfig::new(IMPORT_LIMIT))
+                .route(web::post().to(import)),
+        )
+        .service(resource("/export", Some(Scope::Read)).route(web::get().to(export)))
+        .service(resource("/random", None).route(web::get().to(random)))
+        .service(resource("/daily", None).route(web::get().to(daily)))
+        .service(resource("/tags", None).route(web::get().to(tags)))
+        .service(resource("/search", None).route(web::get().to(search)))
+        .service(resource("/authors", None).route(web::get().to(authors)))
+        .service(resource("/authors/{name}", None).route(web::get().to(author_quotes)))
+        .service(resource("/events", None).route(web::get().to(events)))
+        .service(
+            resource("/keys", Some(Scope::Admin))
+                .route(web::get().to(list_keys))
+                .route(web::post().to(create_key)),
+        )
+        .service(resource("/keys/{id}", Some(Scope::Admin)).route(web::delete().to(revoke_key)))
+}
+
+pub fn app_data() -> web::Data<Mutex<PageTokens>> {
+    PageTokens::new_wrapped()
+}
+
+/// Kept in Postgres along with the quotes, otherwise in this process
+///
+/// See [`ApiKeys::new_wrapped`] for how the first admin key is provided
+pub fn api_keys(pool: PgPool) -> web::Data<ApiKeys> {
+    ApiKeys::new_wrapped(store::uses_postgres().then_some(pool))
+}
+
+/// Only shared through Postgres when the quotes are kept there
+///
+/// See [`QuoteEvents::new_wrapped`] for how events are shared between instances
+pub fn quote_events(pool: PgPool) -> web::Data<QuoteEvents> {
+    QuoteEvents::new_wrapped(store::uses_postgres().then_some(pool))
+}
+
+/// Postgres is only required when the quotes are kept there
+pub fn needs_postgres() -> bool {
+    store::uses_postgres()
+}
+
+/// See [`store::from_env`] for how the backend is chosen
+pub fn quote_store(pool: PgPool) -> web::Data<dyn QuoteStore> {
+    store::from_env(pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote(author: &str, text: &str, tags: &[&str]) -> Quote {
+        Quote {
+            id: Uuid::new_v4(),
+            author: author.to_string(),
+            quote: text.to_string(),
+            created_at: Utc::now(),
+            version: 1,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn csv_round_trips_quoted_fields() {
+        let quotes = [
+            quote(
+                "Santa, Claus",
+                "Ho, ho \"ho\"\nMerry Christmas",
+                &["cheer", "elves"],
+            ),
+            quote("Grinch", "Bah", &[]),
+        ];
+        let body = QuoteFormat::Csv.encode(&quotes, true).unwrap();
+        let (drafts, errors) = QuoteFormat::Csv.parse(std::str::from_utf8(&body).unwrap());
+        assert!(errors.is_empty());
+        assert_eq!(drafts.len(), 2);
+
+        let (line, first) = &drafts[0];
+        assert_eq!(*line, 2);
+        assert_eq!(first.author, "Santa, Claus");
+        assert_eq!(first.quote, "Ho, ho \"ho\"\nMerry Christmas");
+        assert_eq!(first.tags(), Some(vec!["cheer".into(), "elves".into()]));
+        // The first quote spans two lines
+        let (line, second) = &drafts[1];
+        assert_eq!(*line, 4);
+        assert_eq!(second.author, "Grinch");
+    }
+
+    #[test]
+    fn ndjson_reports_bad_lines() {
+        let body = "{\"author\":\"Santa\",\"quote\":\"Ho ho ho\"}\n\
+            \n\
+            {\"author\":\"Grinch\"}\n\
+            {\"author\":\"Elf\",\"quote\":\"Jingle\",\"tags\":[\"bells\"]}\n";
+        let (drafts, errors) = QuoteFormat::JsonLines.parse(body);
+        assert_eq!(
+            drafts.iter().map(|(line, _)| *line).collect::<Vec<_>>(),
+            [1, 4]
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 3);
+        assert!(errors[0].error.contains("quote"), "{}", errors[0].error);
+    }
+
+    #[test]
+    fn content_types() {
+        let parse = |s: &str| QuoteFormat::from_content_type(&ContentType(s.parse().unwrap()));
+        assert!(matches!(parse("text/csv"), Some(QuoteFormat::Csv)));
+        assert!(matches!(
+            parse("text/csv; charset=utf-8"),
+            Some(QuoteFormat::Csv)
+        ));
+        assert!(matches!(
+            parse("application/x-ndjson"),
+            Some(QuoteFormat::JsonLines)
+        ));
+        assert!(parse("application/json").is_none());
+        assert!(parse("text/plain").is_none());
+    }
 }
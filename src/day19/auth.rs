@@ -0,0 +1,461 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error,
+    http::header,
+    middleware::Next,
+    web, HttpResponse,
+};
+use anyhow::Context as _;
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, thread_rng, Rng as _};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use sqlx::PgPool;
+use std::{fmt::Display, str::FromStr, sync::Mutex};
+use tracing::instrument;
+use uuid::Uuid;
+
+/// Prefix of every generated key so they are easy to recognise (eg. by secret scanners)
+const KEY_PREFIX: &str = "cch_";
+const KEY_RANDOM_CHARS: usize = 32;
+
+/// What an API key is allowed to do, each scope includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Scope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "read" => Scope::Read,
+            "write" => Scope::Write,
+            "admin" => Scope::Admin,
+            _ => anyhow::bail!("unknown scope: {s:?}"),
+        })
+    }
+}
+
+/// An API key as it is listed, the key itself is never stored
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKey {
+    id: Uuid,
+    name: String,
+    scope: Scope,
+    created_at: chrono::DateTime<Utc>,
+    revoked_at: Option<chrono::DateTime<Utc>>,
+}
+
+/// An `api_keys` row, the scope is stored as text
+struct ApiKeyRow {
+    id: Uuid,
+    name: String,
+    scope: String,
+    created_at: chrono::DateTime<Utc>,
+    revoked_at: Option<chrono::DateTime<Utc>>,
+}
+
+impl TryFrom<ApiKeyRow> for ApiKey {
+    type Error = actix_web::Error;
+
+    fn try_from(row: ApiKeyRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: row.id,
+            name: row.name,
+            scope: parse_scope(&row.scope)?,
+            created_at: row.created_at,
+            revoked_at: row.revoked_at,
+        })
+    }
+}
+
+/// Only place the key is ever shown, in the response to creating it
+#[derive(Debug, Serialize)]
+pub struct NewApiKey {
+    #[serde(flatten)]
+    details: ApiKey,
+    key: String,
+}
+
+fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+fn generate_key() -> String {
+    let random: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(KEY_RANDOM_CHARS)
+        .map(char::from)
+        .collect();
+    format!("{KEY_PREFIX}{random}")
+}
+
+fn parse_scope(scope: &str) -> actix_web::Result<Scope> {
+    scope.parse().map_err(error::ErrorInternalServerError)
+}
+
+/// A key kept in this process with the hash it is looked up by
+#[derive(Debug)]
+struct StoredKey {
+    key_hash: String,
+    details: ApiKey,
+}
+
+#[derive(Debug)]
+enum KeyStorage {
+    Postgres(PgPool),
+    /// Oldest first, for when the quotes aren't in Postgres either
+    Memory(Mutex<Vec<StoredKey>>),
+}
+
+/// API keys stored hashed in Postgres, or in this process when no pool is given
+#[derive(Debug)]
+pub struct ApiKeys {
+    storage: KeyStorage,
+    /// Hash of `QUOTE_ADMIN_KEY` which is always accepted as an admin key so the first keys can
+    /// be created
+    bootstrap_hash: Option<String>,
+}
+
+impl ApiKeys {
+    pub fn new_wrapped(pool: Option<PgPool>) -> web::Data<Self> {
+        let bootstrap_hash = std::env::var("QUOTE_ADMIN_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+            .map(|key| hash_key(&key));
+        let storage = match pool {
+            Some(pool) => KeyStorage::Postgres(pool),
+            None => KeyStorage::Memory(Mutex::default()),
+        };
+        web::Data::new(Self {
+            storage,
+            bootstrap_hash,
+        })
+    }
+
+    /// `None` if the key is unknown or has been revoked
+    #[instrument(ret, err(Debug), skip(self, key))]
+    async fn scope_of(&self, key: &str) -> actix_web::Result<Option<Scope>> {
+        let key_hash = hash_key(key);
+        if self.bootstrap_hash.as_ref() == Some(&key_hash) {
+            return Ok(Some(Scope::Admin));
+        }
+        let pool = match &self.storage {
+            KeyStorage::Postgres(pool) => pool,
+            KeyStorage::Memory(keys) => {
+                return Ok(keys
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|stored| {
+                        stored.key_hash == key_hash && stored.details.revoked_at.is_none()
+                    })
+                    .map(|stored| stored.details.scope))
+            }
+        };
+        let scope = sqlx::query_scalar!(
+            "SELECT scope FROM api_keys WHERE key_hash = $1 AND revoked_at IS NULL;",
+            key_hash
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+        scope.as_deref().map(parse_scope).transpose()
+    }
+
+    #[instrument(err(Debug), skip(self))]
+    pub async fn create(&self, name: &str, scope: Scope) -> actix_web::Result<NewApiKey> {
+        let key = generate_key();
+        let pool = match &self.storage {
+            KeyStorage::Postgres(pool) => pool,
+            KeyStorage::Memory(keys) => {
+                let details = ApiKey {
+                    id: Uuid::new_v4(),
+                    name: name.to_string(),
+                    scope,
+                    created_at: Utc::now(),
+                    revoked_at: None,
+                };
+                keys.lock().unwrap().push(StoredKey {
+                    key_hash: hash_key(&key),
+                    details: details.clone(),
+                });
+                return Ok(NewApiKey { details, key });
+            }
+        };
+        let row = sqlx::query_as!(
+            ApiKeyRow,
+            "INSERT INTO api_keys (id, name, key_hash, scope) VALUES ($1, $2, $3, $4)
+            RETURNING id, name, scope, created_at, revoked_at;",
+            Uuid::new_v4(),
+            name,
+            hash_key(&key),
+            scope.as_str()
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+        Ok(NewApiKey {
+            details: row.try_into()?,
+            key,
+        })
+    }
+
+    /// Every key including revoked ones, oldest first
+    #[instrument(ret, err(Debug), skip(self))]
+    pub async fn list(&self) -> actix_web::Result<Vec<ApiKey>> {
+        let pool = match &self.storage {
+            KeyStorage::Postgres(pool) => pool,
+            KeyStorage::Memory(keys) => {
+                return Ok(keys
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|stored| stored.details.clone())
+                    .collect())
+            }
+        };
+        sqlx::query_as!(
+            ApiKeyRow,
+            "SELECT id, name, scope, created_at, revoked_at FROM api_keys
+            ORDER BY created_at, id;"
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .into_iter()
+        .map(ApiKey::try_from)
+        .collect()
+    }
+
+    /// Stops the key from being accepted, revoking a key twice keeps the original time
+    #[instrument(ret, err(Debug), skip(self))]
+    pub async fn revoke(&self, id: &Uuid) -> actix_web::Result<ApiKey> {
+        let pool = match &self.storage {
+            KeyStorage::Postgres(pool) => pool,
+            KeyStorage::Memory(keys) => {
+                let mut keys = keys.lock().unwrap();
+                let stored = keys
+                    .iter_mut()
+                    .find(|stored| &stored.details.id == id)
+                    .context("API key not found")
+                    .map_err(error::ErrorNotFound)?;
+                stored.details.revoked_at.get_or_insert_with(Utc::now);
+                return Ok(stored.details.clone());
+            }
+        };
+        sqlx::query_as!(
+            ApiKeyRow,
+            "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, CURRENT_TIMESTAMP)
+            WHERE id = $1
+            RETURNING id, name, scope, created_at, revoked_at;",
+            id
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .context("API key not found")
+        .map_err(error::ErrorNotFound)?
+        .try_into()
+    }
+}
+
+fn unauthorized(message: &'static str) -> actix_web::Error {
+    error::InternalError::from_response(
+        message,
+        HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .body(message),
+    )
+    .into()
+}
+
+/// Rejects requests unless the API key in the `Authorization: Bearer` header has at least the
+/// `required` scope, `None` lets everyone through
+pub async fn require_scope(
+    required: Option<Scope>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    if let Some(required) = required {
+        let keys = req
+            .app_data::<web::Data<ApiKeys>>()
+            .context("API keys not configured")
+            .map_err(error::ErrorInternalServerError)?;
+        let key = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| unauthorized("an API key is required"))?;
+        let scope = keys
+            .scope_of(key.trim())
+            .await?
+            .ok_or_else(|| unauthorized("unknown or revoked API key"))?;
+        if scope < required {
+            return Err(error::ErrorForbidden(format!(
+                "API key needs the {required} scope"
+            )));
+        }
+    }
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::StatusCode,
+        middleware::from_fn,
+        test::{init_service, try_call_service, TestRequest},
+        App,
+    };
+
+    fn memory_keys() -> web::Data<ApiKeys> {
+        web::Data::new(ApiKeys {
+            storage: KeyStorage::Memory(Mutex::default()),
+            bootstrap_hash: None,
+        })
+    }
+
+    /// Status of a GET to `path` with the key, where `/read`, `/write` and `/admin` need that
+    /// scope and `/open` needs none
+    async fn status(keys: &web::Data<ApiKeys>, path: &str, key: Option<&str>) -> StatusCode {
+        let mut app = App::new().app_data(keys.clone());
+        for (path, scope) in [
+            ("/open", None),
+            ("/read", Some(Scope::Read)),
+            ("/write", Some(Scope::Write)),
+            ("/admin", Some(Scope::Admin)),
+        ] {
+            app = app.service(
+                web::resource(path)
+                    .wrap(from_fn(move |req, next| require_scope(scope, req, next)))
+                    .to(HttpResponse::Ok),
+            );
+        }
+        let app = init_service(app).await;
+        let mut request = TestRequest::get().uri(path);
+        if let Some(key) = key {
+            request = request.insert_header((header::AUTHORIZATION, key));
+        }
+        // Errors from middleware come back as errors rather than responses
+        match try_call_service(&app, request.to_request()).await {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[test]
+    fn scopes_are_ordered() {
+        assert!(Scope::Read < Scope::Write);
+        assert!(Scope::Write < Scope::Admin);
+        for scope in [Scope::Read, Scope::Write, Scope::Admin] {
+            assert_eq!(scope.to_string().parse::<Scope>().unwrap(), scope);
+        }
+        assert!("owner".parse::<Scope>().is_err());
+    }
+
+    #[actix_web::test]
+    async fn missing_or_unknown_keys_are_unauthorized() {
+        let keys = memory_keys();
+        let revoked = keys.create("old", Scope::Admin).await.unwrap();
+        keys.revoke(&revoked.details.id).await.unwrap();
+
+        assert_eq!(status(&keys, "/open", None).await, StatusCode::OK);
+        for key in [
+            None,
+            Some("Bearer cch_unknown".to_string()),
+            Some(format!("Basic {}", revoked.key)),
+            Some(format!("Bearer {}", revoked.key)),
+        ] {
+            assert_eq!(
+                status(&keys, "/read", key.as_deref()).await,
+                StatusCode::UNAUTHORIZED,
+                "{key:?}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn lower_scopes_are_forbidden() {
+        let keys = memory_keys();
+        for (scope, allowed) in [
+            (Scope::Read, ["/read"].as_slice()),
+            (Scope::Write, ["/read", "/write"].as_slice()),
+            (Scope::Admin, ["/read", "/write", "/admin"].as_slice()),
+        ] {
+            let key = keys.create("test", scope).await.unwrap().key;
+            let key = format!("Bearer {key}");
+            for path in ["/read", "/write", "/admin"] {
+                let expected = if allowed.contains(&path) {
+                    StatusCode::OK
+                } else {
+                    StatusCode::FORBIDDEN
+                };
+                assert_eq!(
+                    status(&keys, path, Some(&key)).await,
+                    expected,
+                    "{scope} {path}"
+                );
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn keys_are_matched_by_hash() {
+        // SHA-256 of "abc"
+        const ABC_HASH: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(hash_key("abc"), ABC_HASH);
+
+        let keys = memory_keys();
+        let created = keys.create("test", Scope::Write).await.unwrap();
+        assert!(created.key.starts_with(KEY_PREFIX));
+        let KeyStorage::Memory(stored) = &keys.storage else {
+            unreachable!()
+        };
+        {
+            let mut stored = stored.lock().unwrap();
+            assert_eq!(stored[0].key_hash, hash_key(&created.key));
+            assert!(!format!("{stored:?}").contains(&created.key));
+            stored.push(StoredKey {
+                key_hash: ABC_HASH.to_string(),
+                details: ApiKey {
+                    id: Uuid::new_v4(),
+                    name: "abc".to_string(),
+                    scope: Scope::Read,
+                    created_at: Utc::now(),
+                    revoked_at: None,
+                },
+            });
+        }
+        assert_eq!(keys.scope_of("abc").await.unwrap(), Some(Scope::Read));
+        assert_eq!(keys.scope_of(ABC_HASH).await.unwrap(), None);
+        assert_eq!(
+            keys.scope_of(&created.key).await.unwrap(),
+            Some(Scope::Write)
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use actix_web::{rt::time::sleep, web};
+use futures_util::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgListener, PgPool};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, instrument, warn};
+use uuid::Uuid;
+
+use super::Quote;
+
+/// Postgres channel used to share events between instances
+const CHANNEL: &str = "quote_events";
+
+/// How long to wait before trying to listen again after failing to or losing the connection
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Events kept for subscribers that fall behind before they start missing some
+const CAPACITY: usize = 256;
+
+/// A change to the quotes that subscribers of `GET /19/events` are told about
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum QuoteEvent {
+    Created { quote: Quote },
+    Updated { quote: Quote },
+    Deleted { id: Uuid },
+    Reset,
+}
+
+impl QuoteEvent {
+    /// Used as the SSE event name so clients can listen for only some kinds of events
+    pub fn name(&self) -> &'static str {
+        match self {
+            QuoteEvent::Created { .. } => "created",
+            QuoteEvent::Updated { .. } => "updated",
+            QuoteEvent::Deleted { .. } => "deleted",
+            QuoteEvent::Reset => "reset",
+        }
+    }
+
+    fn to_sse(&self) -> serde_json::Result<web::Bytes> {
+        let data = serde_json::to_string(self)?;
+        Ok(format!("event: {}\ndata: {data}\n\n", self.name()).into())
+    }
+}
+
+/// Fans out [`QuoteEvent`]s to every subscriber in this process
+///
+/// With fan-out through Postgres, events are sent with NOTIFY and only delivered locally once
+/// they come back from LISTEN so every instance sees the same events
+#[derive(Debug)]
+pub struct QuoteEvents {
+    sender: broadcast::Sender<QuoteEvent>,
+    /// Set when events are shared with other instances through Postgres
+    pool: Option<PgPool>,
+    /// Whether LISTEN has succeeded, until then events are delivered locally as they wouldn't
+    /// come back
+    listening: Arc<AtomicBool>,
+}
+
+impl QuoteEvents {
+    /// Local only, unless `QUOTE_EVENTS` is set to `postgres` and a pool is given
+    ///
+    /// Must be called from within a Tokio runtime as fan-out spawns a task to listen for events
+    pub fn new_wrapped(pool: Option<PgPool>) -> web::Data<Self> {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        let fan_out = std::env::var("QUOTE_EVENTS").is_ok_and(|setting| setting == "postgres");
+        let listening = Arc::new(AtomicBool::new(false));
+        let pool = match pool {
+            Some(pool) if fan_out => {
+                tokio::spawn(listen(pool.clone(), sender.clone(), listening.clone()));
+                Some(pool)
+            }
+            None if fan_out => {
+                warn!("QUOTE_EVENTS=postgres needs the quotes in Postgres, events stay local");
+                None
+            }
+            _ => None,
+        };
+        web::Data::new(Self {
+            sender,
+            pool,
+            listening,
+        })
+    }
+
+    /// Events from now on encoded as Server-Sent Events
+    ///
+    /// Subscribers that fall too far behind get a `lagged` event with the number of events missed
+    pub fn subscribe(&self) -> impl Stream<Item = serde_json::Result<web::Bytes>> {
+        stream::unfold(self.sender.subscribe(), |mut receiver| async move {
+            let chunk = match receiver.recv().await {
+                Ok(event) => event.to_sse(),
+                Err(RecvError::Lagged(missed)) => {
+                    Ok(format!("event: lagged\ndata: {{\"missed\":{missed}}}\n\n").into())
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            Some((chunk, receiver))
+        })
+    }
+
+    /// Failing to publish is only logged as the change it describes has already been made
+    #[instrument(skip(self))]
+    pub async fn publish(&self, event: QuoteEvent) {
+        let pool = match &self.pool {
+            Some(pool) if self.listening.load(Ordering::Acquire) => pool,
+            _ => {
+                // Only fails if nobody is subscribed
+                let _ = self.sender.send(event);
+                return;
+            }
+        };
+        let payload = match serde_json::to_string(&event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!(?e, "failed to serialize event");
+                return;
+            }
+        };
+        if let Err(e) = sqlx::query("SELECT pg_notify($1, $2);")
+            .bind(CHANNEL)
+            .bind(payload)
+            .execute(pool)
+            .await
+        {
+            error!(
+                ?e,
+                "failed to notify other instances, delivering locally only"
+            );
+            let _ = self.sender.send(event);
+        }
+    }
+}
+
+/// Connects and starts listening, retrying until it succeeds
+async fn start_listening(pool: &PgPool) -> PgListener {
+    loop {
+        match PgListener::connect_with(pool).await {
+            Ok(mut listener) => match listener.listen(CHANNEL).await {
+                Ok(()) => return listener,
+                Err(e) => error!(?e, "failed to listen for events, retrying"),
+            },
+            Err(e) => error!(?e, "failed to connect listener, retrying"),
+        }
+        sleep(RETRY_DELAY).await;
+    }
+}
+
+/// Forwards events from every instance (including this one) to local subscribers
+async fn listen(pool: PgPool, sender: broadcast::Sender<QuoteEvent>, listening: Arc<AtomicBool>) {
+    let mut listener = start_listening(&pool).await;
+    listening.store(true, Ordering::Release);
+    loop {
+        // The listener reconnects on the next call after an error
+        match listener.recv().await {
+            Ok(notification) => match serde_json::from_str(notification.payload()) {
+                Ok(event) => {
+                    let _ = sender.send(event);
+                }
+                Err(e) => warn!(?e, "ignoring unrecognised event"),
+            },
+            Err(e) => {
+                error!(?e, "lost connection to listen for events");
+                sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use actix_web::{
+    dev::Payload,
+    error,
+    http::header::{self, Header as _, IfMatch},
+    web, FromRequest, HttpRequest,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use sqlx::PgPool;
+use std::{
+    fmt::Debug,
+    future::{ready, Ready},
+    sync::Arc,
+};
+use uuid::Uuid;
+
+use super::{
+    AuthorStats, DraftQuote, Quote, QuoteVersion, SearchResult, TagCount, TrashedQuote,
+    HIGHLIGHT_START, HIGHLIGHT_STOP,
+};
+
+mod memory;
+mod postgres;
+mod sqlite;
+
+#[cfg(test)]
+pub(super) use memory::MemoryStore;
+
+/// Restricts which quotes are returned by [`QuoteStore::list`]
+#[derive(Debug, Clone, Default)]
+pub struct QuoteFilter {
+    /// Only quotes by exactly this author
+    pub author: Option<String>,
+    /// Only quotes containing these words, matched the same way as by [`QuoteStore::search`]
+    pub q: Option<String>,
+    /// Only quotes with this tag
+    pub tag: Option<String>,
+}
+
+impl QuoteFilter {
+    fn matches(&self, quote: &Quote) -> bool {
+        self.author
+            .as_ref()
+            .is_none_or(|author| &quote.author == author)
+            && self.q.as_ref().is_none_or(|q| {
+                let terms: Vec<String> = words(q).collect();
+                rank(quote, &terms).is_some()
+            })
+            && self.tag.as_ref().is_none_or(|tag| quote.tags.contains(tag))
+    }
+}
+
+/// Creation times for quotes added together, a microsecond apart so they are listed in the order
+/// they were given
+fn creation_times() -> impl Iterator<Item = DateTime<Utc>> {
+    let start = Utc::now();
+    (0..).map(move |i| start + TimeDelta::microseconds(i))
+}
+
+/// How much a word in the author's name counts compared to a word in the quote
+const AUTHOR_WEIGHT: f32 = 0.4;
+
+/// Lower cased words of the text, anything that isn't a letter or digit separates words
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Ranks a quote containing every term, favouring short quotes and authors with many matches
+fn rank(quote: &Quote, terms: &[String]) -> Option<f32> {
+    let quote_words: Vec<String> = words(&quote.quote).collect();
+    let author_words: Vec<String> = words(&quote.author).collect();
+    let mut rank = 0.0;
+    for term in terms {
+        let in_quote = quote_words.iter().filter(|word| *word == term).count();
+        let in_author = author_words.iter().filter(|word| *word == term).count();
+        if in_quote + in_author == 0 {
+            return None;
+        }
+        rank += in_quote as f32 + AUTHOR_WEIGHT * in_author as f32;
+    }
+    Some(rank / (quote_words.len() + author_words.len()) as f32)
+}
+
+/// The text with every word that is one of the terms highlighted
+fn highlight(text: &str, terms: &[String]) -> String {
+    let mut result = String::with_capacity(text.len());
+    for segment in text.split_inclusive(|c: char| !c.is_alphanumeric()) {
+        let word = segment.trim_end_matches(|c: char| !c.is_alphanumeric());
+        if !word.is_empty() && terms.contains(&word.to_lowercase()) {
+            result.push_str(HIGHLIGHT_START);
+            result.push_str(word);
+            result.push_str(HIGHLIGHT_STOP);
+            result.push_str(&segment[word.len()..]);
+        } else {
+            result.push_str(segment);
+        }
+    }
+    result
+}
+
+/// Search for backends without full-text search, only whole words match and every word must
+/// match
+fn search_quotes(quotes: Vec<Quote>, q: &str, limit: i64) -> Vec<SearchResult> {
+    let terms: Vec<String> = words(q).collect();
+    if terms.is_empty() {
+        return vec![];
+    }
+    let mut results: Vec<SearchResult> = quotes
+        .into_iter()
+        .filter_map(|quote| {
+            let rank = rank(&quote, &terms)?;
+            Some(SearchResult {
+                snippet: highlight(&quote.quote, &terms),
+                quote,
+                rank,
+            })
+        })
+        .collect();
+    // Stable so equal ranks stay in the order they were created
+    results.sort_by(|a, b| b.rank.total_cmp(&a.rank));
+    results.truncate(limit.try_into().unwrap_or(usize::MAX));
+    results
+}
+
+/// Versions of a quote that a write is allowed to apply to, built from an `If-Match` header
+#[derive(Debug, Default)]
+pub struct Precondition {
+    /// `None` if any version is acceptable
+    versions: Option<Vec<i32>>,
+}
+
+impl Precondition {
+    /// Fails if any of the tags can't be parsed, actix skips those which would turn a malformed
+    /// header (eg. the unquoted `If-Match: 3`) into a missing one and let the write through
+    fn from_if_match(req: &HttpRequest) -> actix_web::Result<Self> {
+        let raw_tags = req
+            .headers()
+            .get_all(header::IF_MATCH)
+            .map(|value| value.to_str().map_err(error::ErrorBadRequest))
+            .collect::<actix_web::Result<Vec<_>>>()?
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .filter(|tag| !tag.trim().is_empty())
+            .count();
+        let versions = match IfMatch::parse(req).map_err(error::ErrorBadRequest)? {
+            IfMatch::Any => None,
+            // A missing header is parsed as an empty list of tags
+            IfMatch::Items(_) if raw_tags == 0 => None,
+            IfMatch::Items(tags) if tags.len() != raw_tags => {
+                return Err(error::ErrorBadRequest(
+                    "If-Match must be * or a list of quoted entity tags",
+                ))
+            }
+            // Only strong tags can match, anything else we never handed out
+            IfMatch::Items(tags) => Some(
+                tags.iter()
+                    .filter(|tag| !tag.weak)
+                    .filter_map(|tag| tag.tag().parse().ok())
+                    .collect(),
+            ),
+        };
+        Ok(Self { versions })
+    }
+}
+
+/// Taken from the `If-Match` header, malformed headers are rejected with 400 Bad Request
+impl FromRequest for Precondition {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::from_if_match(req))
+    }
+}
+
+impl Precondition {
+    /// Fails with 412 Precondition Failed if the quote is not at one of the expected versions
+    fn check(&self, quote: &Quote) -> actix_web::Result<()> {
+        match &self.versions {
+            Some(versions) if !versions.contains(&quote.version) => {
+                Err(error::ErrorPreconditionFailed(format!(
+                    "quote has moved on to version {}",
+                    quote.version
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Storage for quotes and their history
+///
+/// Removing quotes only moves them to the trash so they can be restored later
+#[async_trait]
+pub trait QuoteStore: Debug + Send + Sync {
+    /// Returns the quote unless it doesn't exist or is in the trash
+    async fn get(&self, id: &Uuid) -> actix_web::Result<Quote>;
+
+    /// Creates all the quotes or none of them
+    async fn insert_all(&self, drafts: &[DraftQuote]) -> actix_web::Result<Vec<Quote>>;
+
+    async fn insert(&self, draft: &DraftQuote) -> actix_web::Result<Quote> {
+        Ok(self
+            .insert_all(std::slice::from_ref(draft))
+            .await?
+            .pop()
+            .expect("one quote is created per draft"))
+    }
+
+    /// Replaces the contents of the quote as a new version, tags are only replaced if provided
+    async fn update(
+        &self,
+        id: &Uuid,
+        draft: &DraftQuote,
+        precondition: &Precondition,
+    ) -> actix_web::Result<Quote>;
+
+    /// Restores the contents of an earlier version as a new version, tags are not versioned
+    async fn revert(&self, id: &Uuid, version: i32) -> actix_web::Result<Quote>;
+
+    /// Moves the quote to the trash and returns it
+    async fn delete(&self, id: &Uuid, precondition: &Precondition) -> actix_web::Result<Quote>;
+
+    /// Moves every quote to the trash
+    async fn truncate(&self) -> actix_web::Result<()>;
+
+    /// Quotes matching the filter ordered by when they were created
+    async fn list(
+        &self,
+        filter: &QuoteFilter,
+        offset: i64,
+        limit: i64,
+    ) -> actix_web::Result<Vec<Quote>>;
+
+    /// Up to `limit` quotes containing the words in `q`, best matches first
+    ///
+    /// By default every quote is loaded and searched in this process
+    async fn search(&self, q: &str, limit: i64) -> actix_web::Result<Vec<SearchResult>> {
+        let quotes = self.list(&QuoteFilter::default(), 0, i64::MAX).await?;
+        Ok(search_quotes(quotes, q, limit))
+    }
+
+    /// Number of quotes matching the filter
+    async fn count(&self, filter: &QuoteFilter) -> actix_web::Result<i64>;
+
+    /// Up to `limit` quotes matching the filter created after the one identified by `after`
+    /// (`created_at`, `id`)
+    ///
+    /// Unlike paging with an offset, quotes added or removed in between don't shift the next page
+    async fn list_after(
+        &self,
+        filter: &QuoteFilter,
+        after: Option<(chrono::DateTime<Utc>, Uuid)>,
+        limit: i64,
+    ) -> actix_web::Result<Vec<Quote>>;
+
+    /// Tags in use by quotes not in the trash, most used first
+    async fn tags(&self) -> actix_web::Result<Vec<TagCount>>;
+
+    /// Statistics for every author with quotes not in the trash, most prolific first
+    async fn authors(&self) -> actix_web::Result<Vec<AuthorStats>>;
+
+    /// Every version of the quote, oldest first
+    async fn history(&self, id: &Uuid) -> actix_web::Result<Vec<QuoteVersion>>;
+
+    /// Another quote not in the trash with exactly this author and text, ignoring `except`
+    async fn find_duplicate(
+        &self,
+        author: &str,
+        quote: &str,
+        except: Option<&Uuid>,
+    ) -> actix_web::Result<Option<Uuid>>;
+
+    /// Quotes in the trash, most recently removed first
+    async fn trash(&self) -> actix_web::Result<Vec<TrashedQuote>>;
+
+    /// Takes the quote back out of the trash
+    async fn restore(&self, id: &Uuid) -> actix_web::Result<Quote>;
+}
+
+fn setting() -> String {
+    std::env::var("QUOTE_STORE").unwrap_or_default()
+}
+
+/// Whether [`from_env`] picks the Postgres backend
+pub fn uses_postgres() -> bool {
+    let setting = setting();
+    setting != "memory" && !setting.starts_with("sqlite:")
+}
+
+/// Picks the backend using the `QUOTE_STORE` environment variable
+///
+/// `memory` keeps quotes in this process only, a `sqlite:` URL (eg. `sqlite::memory:` or
+/// `sqlite://quotes.db`) uses SQLite and anything else uses the Postgres pool
+pub fn from_env(pool: PgPool) -> web::Data<dyn QuoteStore> {
+    let setting = setting();
+    let store: Arc<dyn QuoteStore> = if setting == "memory" {
+        Arc::new(memory::MemoryStore::default())
+    } else if setting.starts_with("sqlite:") {
+        Arc::new(sqlite::SqliteStore::new_lazy(&setting).expect("Failed to configure SQLite"))
+    } else {
+        Arc::new(postgres::PostgresStore::new(pool))
+    };
+    web::Data::from(store)
+}
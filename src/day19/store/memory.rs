@@ -0,0 +1,574 @@
+use actix_web::error;
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::Utc;
+use std::{collections::HashMap, sync::Mutex};
+use tracing::instrument;
+use uuid::Uuid;
+
+use super::{creation_times, Precondition, QuoteFilter, QuoteStore};
+use crate::day19::{AuthorStats, DraftQuote, Quote, QuoteVersion, TagCount, TrashedQuote};
+
+/// Keeps everything in this process, useful for local development and tests
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    quotes: Mutex<HashMap<Uuid, StoredQuote>>,
+}
+
+#[derive(Debug)]
+struct StoredQuote {
+    quote: Quote,
+    deleted_at: Option<chrono::DateTime<Utc>>,
+    /// Oldest first, the last entry matches `quote`
+    versions: Vec<QuoteVersion>,
+}
+
+impl StoredQuote {
+    fn new(draft: &DraftQuote, created_at: chrono::DateTime<Utc>) -> Self {
+        let quote = Quote {
+            id: Uuid::new_v4(),
+            author: draft.author.clone(),
+            quote: draft.quote.clone(),
+            created_at,
+            version: 1,
+            tags: draft.tags().unwrap_or_default(),
+        };
+        Self {
+            versions: vec![QuoteVersion::from_quote(&quote, created_at)],
+            quote,
+            deleted_at: None,
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        self.deleted_at.is_none()
+    }
+
+    /// Replaces the contents as a new version and records it in the history
+    fn apply(&mut self, author: String, quote: String, tags: Option<Vec<String>>) -> Quote {
+        self.quote.author = author;
+        self.quote.quote = quote;
+        self.quote.version += 1;
+        if let Some(tags) = tags {
+            self.quote.tags = tags;
+        }
+        self.versions
+            .push(QuoteVersion::from_quote(&self.quote, Utc::now()));
+        self.quote.clone()
+    }
+}
+
+impl QuoteVersion {
+    fn from_quote(quote: &Quote, created_at: chrono::DateTime<Utc>) -> Self {
+        Self {
+            version: quote.version,
+            author: quote.author.clone(),
+            quote: quote.quote.clone(),
+            created_at,
+        }
+    }
+}
+
+fn live_mut<'a>(
+    quotes: &'a mut HashMap<Uuid, StoredQuote>,
+    id: &Uuid,
+) -> actix_web::Result<&'a mut StoredQuote> {
+    quotes
+        .get_mut(id)
+        .filter(|stored| stored.is_live())
+        .context("id not found")
+        .map_err(error::ErrorNotFound)
+}
+
+/// Quotes not in the trash ordered the same way as the database backends order them
+fn live_sorted(quotes: &HashMap<Uuid, StoredQuote>) -> Vec<&Quote> {
+    let mut result: Vec<&Quote> = quotes
+        .values()
+        .filter(|stored| stored.is_live())
+        .map(|stored| &stored.quote)
+        .collect();
+    result.sort_by_key(|quote| (quote.created_at, quote.id));
+    result
+}
+
+#[async_trait]
+impl QuoteStore for MemoryStore {
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn get(&self, id: &Uuid) -> actix_web::Result<Quote> {
+        let mut quotes = self.quotes.lock().unwrap();
+        Ok(live_mut(&mut quotes, id)?.quote.clone())
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn insert_all(&self, drafts: &[DraftQuote]) -> actix_web::Result<Vec<Quote>> {
+        let mut quotes = self.quotes.lock().unwrap();
+        Ok(drafts
+            .iter()
+            .zip(creation_times())
+            .map(|(draft, created_at)| {
+                let stored = StoredQuote::new(draft, created_at);
+                let quote = stored.quote.clone();
+                quotes.insert(quote.id, stored);
+                quote
+            })
+            .collect())
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn update(
+        &self,
+        id: &Uuid,
+        draft: &DraftQuote,
+        precondition: &Precondition,
+    ) -> actix_web::Result<Quote> {
+        let mut quotes = self.quotes.lock().unwrap();
+        let stored = live_mut(&mut quotes, id)?;
+        precondition.check(&stored.quote)?;
+        Ok(stored.apply(draft.author.clone(), draft.quote.clone(), draft.tags()))
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn revert(&self, id: &Uuid, version: i32) -> actix_web::Result<Quote> {
+        let mut quotes = self.quotes.lock().unwrap();
+        let stored = live_mut(&mut quotes, id)?;
+        let old = stored
+            .versions
+            .iter()
+            .find(|old| old.version == version)
+            .context("version not found")
+            .map_err(error::ErrorNotFound)?;
+        let (author, quote) = (old.author.clone(), old.quote.clone());
+        Ok(stored.apply(author, quote, None))
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn delete(&self, id: &Uuid, precondition: &Precondition) -> actix_web::Result<Quote> {
+        let mut quotes = self.quotes.lock().unwrap();
+        let stored = live_mut(&mut quotes, id)?;
+        precondition.check(&stored.quote)?;
+        stored.deleted_at = Some(Utc::now());
+        Ok(stored.quote.clone())
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn truncate(&self) -> actix_web::Result<()> {
+        let now = Utc::now();
+        self.quotes
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter(|stored| stored.is_live())
+            .for_each(|stored| stored.deleted_at = Some(now));
+        Ok(())
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn list(
+        &self,
+        filter: &QuoteFilter,
+        offset: i64,
+        limit: i64,
+    ) -> actix_web::Result<Vec<Quote>> {
+        let quotes = self.quotes.lock().unwrap();
+        Ok(live_sorted(&quotes)
+            .into_iter()
+            .filter(|quote| filter.matches(quote))
+            .skip(offset.try_into().unwrap_or_default())
+            .take(limit.try_into().unwrap_or_default())
+            .cloned()
+            .collect())
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn count(&self, filter: &QuoteFilter) -> actix_web::Result<i64> {
+        let quotes = self.quotes.lock().unwrap();
+        let count = quotes
+            .values()
+            .filter(|stored| stored.is_live() && filter.matches(&stored.quote))
+            .count();
+        Ok(count.try_into().unwrap_or(i64::MAX))
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn list_after(
+        &self,
+        filter: &QuoteFilter,
+        after: Option<(chrono::DateTime<Utc>, Uuid)>,
+        limit: i64,
+    ) -> actix_web::Result<Vec<Quote>> {
+        let quotes = self.quotes.lock().unwrap();
+        Ok(live_sorted(&quotes)
+            .into_iter()
+            .filter(|quote| after.is_none_or(|after| (quote.created_at, quote.id) > after))
+            .filter(|quote| filter.matches(quote))
+            .take(limit.try_into().unwrap_or_default())
+            .cloned()
+            .collect())
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn tags(&self) -> actix_web::Result<Vec<TagCount>> {
+        let quotes = self.quotes.lock().unwrap();
+        let mut counts: HashMap<&str, i64> = HashMap::new();
+        for tag in live_sorted(&quotes)
+            .into_iter()
+            .flat_map(|quote| &quote.tags)
+        {
+            *counts.entry(tag).or_default() += 1;
+        }
+        let mut result: Vec<TagCount> = counts
+            .into_iter()
+            .map(|(tag, count)| TagCount {
+                tag: tag.to_string(),
+                count,
+            })
+            .collect();
+        result.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+        Ok(result)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn authors(&self) -> actix_web::Result<Vec<AuthorStats>> {
+        let quotes = self.quotes.lock().unwrap();
+        let mut stats: HashMap<&str, AuthorStats> = HashMap::new();
+        for quote in live_sorted(&quotes) {
+            let entry = stats.entry(&quote.author).or_insert_with(|| AuthorStats {
+                author: quote.author.clone(),
+                quotes: 0,
+                first_created_at: quote.created_at,
+                last_created_at: quote.created_at,
+                edits: 0,
+            });
+            entry.quotes += 1;
+            entry.first_created_at = entry.first_created_at.min(quote.created_at);
+            entry.last_created_at = entry.last_created_at.max(quote.created_at);
+            entry.edits += i64::from(quote.version - 1);
+        }
+        let mut result: Vec<AuthorStats> = stats.into_values().collect();
+        result.sort_by(|a, b| {
+            b.quotes
+                .cmp(&a.quotes)
+                .then_with(|| a.author.cmp(&b.author))
+        });
+        Ok(result)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn history(&self, id: &Uuid) -> actix_web::Result<Vec<QuoteVersion>> {
+        let mut quotes = self.quotes.lock().unwrap();
+        Ok(live_mut(&mut quotes, id)?.versions.clone())
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn find_duplicate(
+        &self,
+        author: &str,
+        quote: &str,
+        except: Option<&Uuid>,
+    ) -> actix_web::Result<Option<Uuid>> {
+        let quotes = self.quotes.lock().unwrap();
+        Ok(quotes
+            .values()
+            .filter(|stored| stored.is_live() && Some(&stored.quote.id) != except)
+            .find(|stored| stored.quote.author == author && stored.quote.quote == quote)
+            .map(|stored| stored.quote.id))
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn trash(&self) -> actix_web::Result<Vec<TrashedQuote>> {
+        let quotes = self.quotes.lock().unwrap();
+        let mut result: Vec<TrashedQuote> = quotes
+            .values()
+            .filter_map(|stored| {
+                let deleted_at = stored.deleted_at?;
+                let quote = stored.quote.clone();
+                Some(TrashedQuote {
+                    id: quote.id,
+                    author: quote.author,
+                    quote: quote.quote,
+                    created_at: quote.created_at,
+                    version: quote.version,
+                    deleted_at,
+                })
+            })
+            .collect();
+        result.sort_by_key(|trashed| (std::cmp::Reverse(trashed.deleted_at), trashed.id));
+        Ok(result)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn restore(&self, id: &Uuid) -> actix_web::Result<Quote> {
+        let mut quotes = self.quotes.lock().unwrap();
+        let stored = quotes
+            .get_mut(id)
+            .filter(|stored| !stored.is_live())
+            .context("id not found in trash")
+            .map_err(error::ErrorNotFound)?;
+        stored.deleted_at = None;
+        Ok(stored.quote.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header, StatusCode},
+        test::TestRequest,
+    };
+
+    use super::*;
+
+    fn draft(author: &str, quote: &str) -> DraftQuote {
+        DraftQuote {
+            author: author.to_string(),
+            quote: quote.to_string(),
+            tags: None,
+        }
+    }
+
+    fn if_match(value: &str) -> Precondition {
+        let req = TestRequest::default()
+            .insert_header((header::IF_MATCH, value))
+            .to_http_request();
+        Precondition::from_if_match(&req).unwrap()
+    }
+
+    fn status(error: actix_web::Error) -> StatusCode {
+        error.as_response_error().status_code()
+    }
+
+    #[actix_web::test]
+    async fn get() {
+        let store = MemoryStore::default();
+        let quote = store.insert(&draft("Santa", "Ho ho ho")).await.unwrap();
+
+        let found = store.get(&quote.id).await.unwrap();
+        assert_eq!(found.quote, "Ho ho ho");
+        assert_eq!(found.version, 1);
+        let missing = store.get(&Uuid::new_v4()).await.unwrap_err();
+        assert_eq!(status(missing), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn update_adds_a_version() {
+        let store = MemoryStore::default();
+        let quote = store.insert(&draft("Santa", "Ho ho ho")).await.unwrap();
+
+        let updated = store
+            .update(
+                &quote.id,
+                &draft("Santa", "Ho ho"),
+                &Precondition::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated.version, 2);
+        assert_eq!(store.get(&quote.id).await.unwrap().quote, "Ho ho");
+        let history = store.history(&quote.id).await.unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|version| version.quote.as_str())
+                .collect::<Vec<_>>(),
+            ["Ho ho ho", "Ho ho"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn update_checks_precondition() {
+        let store = MemoryStore::default();
+        let quote = store.insert(&draft("Santa", "Ho ho ho")).await.unwrap();
+        store
+            .update(&quote.id, &draft("Santa", "Ho ho"), &if_match("\"1\""))
+            .await
+            .unwrap();
+
+        let stale = store
+            .update(&quote.id, &draft("Santa", "Ho"), &if_match("\"1\""))
+            .await
+            .unwrap_err();
+        assert_eq!(status(stale), StatusCode::PRECONDITION_FAILED);
+        let weak = store
+            .update(&quote.id, &draft("Santa", "Ho"), &if_match("W/\"2\""))
+            .await
+            .unwrap_err();
+        assert_eq!(status(weak), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(store.get(&quote.id).await.unwrap().version, 2);
+
+        let any = store
+            .update(&quote.id, &draft("Santa", "Ho"), &if_match("*"))
+            .await
+            .unwrap();
+        assert_eq!(any.version, 3);
+        let listed = store
+            .update(&quote.id, &draft("Santa", "Hi"), &if_match("\"1\", \"3\""))
+            .await
+            .unwrap();
+        assert_eq!(listed.version, 4);
+    }
+
+    #[actix_web::test]
+    async fn delete_and_restore() {
+        let store = MemoryStore::default();
+        let quote = store.insert(&draft("Santa", "Ho ho ho")).await.unwrap();
+
+        let stale = store
+            .delete(&quote.id, &if_match("\"2\""))
+            .await
+            .unwrap_err();
+        assert_eq!(status(stale), StatusCode::PRECONDITION_FAILED);
+        store.delete(&quote.id, &if_match("\"1\"")).await.unwrap();
+        assert_eq!(
+            status(store.get(&quote.id).await.unwrap_err()),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(
+                store
+                    .delete(&quote.id, &Precondition::default())
+                    .await
+                    .unwrap_err()
+            ),
+            StatusCode::NOT_FOUND
+        );
+        let trash = store.trash().await.unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].id, quote.id);
+
+        let restored = store.restore(&quote.id).await.unwrap();
+        assert_eq!(restored.quote, "Ho ho ho");
+        assert_eq!(store.get(&quote.id).await.unwrap().version, 1);
+        assert!(store.trash().await.unwrap().is_empty());
+        assert_eq!(
+            status(store.restore(&quote.id).await.unwrap_err()),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn imports_keep_their_order() {
+        let store = MemoryStore::default();
+        let drafts: Vec<_> = (1..=20)
+            .map(|i| draft("Santa", &format!("Quote {i}")))
+            .collect();
+        store.insert_all(&drafts).await.unwrap();
+
+        let listed = store
+            .list_after(&QuoteFilter::default(), None, i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            listed.iter().map(|quote| &quote.quote).collect::<Vec<_>>(),
+            drafts.iter().map(|draft| &draft.quote).collect::<Vec<_>>()
+        );
+    }
+
+    #[actix_web::test]
+    async fn list_pages() {
+        let store = MemoryStore::default();
+        let drafts: Vec<_> = (1..=5)
+            .map(|i| draft("Santa", &format!("Quote {i}")))
+            .collect();
+        store.insert_all(&drafts).await.unwrap();
+        let removed = store.insert(&draft("Grinch", "Bah")).await.unwrap();
+        store
+            .delete(&removed.id, &Precondition::default())
+            .await
+            .unwrap();
+
+        let filter = QuoteFilter::default();
+        let all = store.list(&filter, 0, i64::MAX).await.unwrap();
+        assert_eq!(all.len(), 5);
+        assert_eq!(store.count(&filter).await.unwrap(), 5);
+        let mut paged = vec![];
+        for offset in (0..6).step_by(2) {
+            let page = store.list(&filter, offset, 2).await.unwrap();
+            assert!(page.len() <= 2);
+            paged.extend(page);
+        }
+        assert_eq!(
+            paged.iter().map(|quote| quote.id).collect::<Vec<_>>(),
+            all.iter().map(|quote| quote.id).collect::<Vec<_>>()
+        );
+        assert!(store.list(&filter, 5, 2).await.unwrap().is_empty());
+
+        let mut after = None;
+        let mut cursored = vec![];
+        loop {
+            let page = store.list_after(&filter, after, 2).await.unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = Some((last.created_at, last.id));
+            cursored.extend(page.into_iter().map(|quote| quote.id));
+        }
+        assert_eq!(
+            cursored,
+            all.iter().map(|quote| quote.id).collect::<Vec<_>>()
+        );
+    }
+
+    #[actix_web::test]
+    async fn q_matches_whole_words() {
+        let store = MemoryStore::default();
+        let drafts = [
+            draft("Santa", "Ho ho ho, merry Christmas"),
+            draft("Grinch", "Christmas is a humbug"),
+            draft("Elf", "Merrily we go"),
+        ];
+        store.insert_all(&drafts).await.unwrap();
+
+        let authors = |q: &str| {
+            let filter = QuoteFilter {
+                q: Some(q.to_string()),
+                ..Default::default()
+            };
+            let store = &store;
+            async move {
+                let mut authors: Vec<_> = store
+                    .list_after(&filter, None, i64::MAX)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|quote| quote.author)
+                    .collect();
+                authors.sort();
+                authors
+            }
+        };
+        assert_eq!(authors("christmas").await, ["Grinch", "Santa"]);
+        assert_eq!(authors("MERRY christmas").await, ["Santa"]);
+        assert_eq!(authors("merr").await, Vec::<String>::new());
+        assert_eq!(authors("elf").await, ["Elf"]);
+    }
+
+    #[actix_web::test]
+    async fn pages_continue_after_changes() {
+        let store = MemoryStore::default();
+        let mut quotes = vec![];
+        for i in 1..=4 {
+            quotes.push(
+                store
+                    .insert(&draft("Santa", &format!("Quote {i}")))
+                    .await
+                    .unwrap(),
+            );
+        }
+        let filter = QuoteFilter::default();
+        let first = store.list_after(&filter, None, 2).await.unwrap();
+        let last = first.last().unwrap();
+        let after = Some((last.created_at, last.id));
+
+        // Removing a quote already seen and adding a new one
+        store
+            .delete(&first[0].id, &Precondition::default())
+            .await
+            .unwrap();
+        store.insert(&draft("Santa", "Quote 5")).await.unwrap();
+
+        let second = store.list_after(&filter, after, 10).await.unwrap();
+        let texts: Vec<_> = second.iter().map(|quote| quote.quote.as_str()).collect();
+        assert!(texts.starts_with(&["Quote 3", "Quote 4"]), "{texts:?}");
+        assert!(!texts.contains(&"Quote 2"));
+    }
+}
@@ -0,0 +1,497 @@
+use actix_web::error;
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use tracing::instrument;
+use uuid::Uuid;
+
+use super::{Precondition, QuoteFilter, QuoteStore};
+use crate::day19::{
+    AuthorStats, DraftQuote, Quote, QuoteVersion, SearchResult, TagCount, TrashedQuote,
+    HIGHLIGHT_START, HIGHLIGHT_STOP,
+};
+
+#[derive(Debug)]
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[instrument(ret, err(Debug), skip(conn))]
+async fn get_quote<'c>(conn: impl PgExecutor<'c>, id: &Uuid) -> actix_web::Result<Quote> {
+    sqlx::query_as!(
+        Quote,
+        r#"SELECT id, author, quote, created_at, version,
+        ARRAY(
+            SELECT t.name FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+            WHERE qt.quote_id = quotes.id ORDER BY t.name
+        ) as "tags!"
+        FROM quotes
+        WHERE id = $1 AND deleted_at IS NULL;"#,
+        id
+    )
+    .fetch_optional(conn)
+    .await
+    .map_err(error::ErrorInternalServerError)?
+    .context("id not found")
+    .map_err(error::ErrorNotFound)
+}
+
+/// Same as [`get_quote`] but locks the row until the transaction ends
+#[instrument(ret, err(Debug), skip(conn))]
+async fn get_quote_for_update(conn: &mut PgConnection, id: &Uuid) -> actix_web::Result<Quote> {
+    sqlx::query_as!(
+        Quote,
+        r#"SELECT id, author, quote, created_at, version,
+        ARRAY(
+            SELECT t.name FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+            WHERE qt.quote_id = quotes.id ORDER BY t.name
+        ) as "tags!"
+        FROM quotes
+        WHERE id = $1 AND deleted_at IS NULL
+        FOR UPDATE;"#,
+        id
+    )
+    .fetch_optional(conn)
+    .await
+    .map_err(error::ErrorInternalServerError)?
+    .context("id not found")
+    .map_err(error::ErrorNotFound)
+}
+
+/// Saves the quote's current contents as an entry in its history
+#[instrument(ret, err(Debug), skip(conn))]
+async fn record_version(conn: &mut PgConnection, quote: &Quote) -> actix_web::Result<()> {
+    sqlx::query!(
+        "INSERT INTO quote_versions
+        (quote_id, version, author, quote)
+        values ($1, $2, $3, $4);",
+        quote.id,
+        quote.version,
+        quote.author,
+        quote.quote
+    )
+    .execute(conn)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(())
+}
+
+/// Replaces all the tags on the quote, creating any tags that don't exist yet
+#[instrument(ret, err(Debug), skip(conn))]
+async fn set_tags(conn: &mut PgConnection, id: &Uuid, tags: &[String]) -> actix_web::Result<()> {
+    sqlx::query!("DELETE FROM quote_tags WHERE quote_id = $1;", id)
+        .execute(&mut *conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    sqlx::query!(
+        "INSERT INTO tags (name) SELECT UNNEST($1::TEXT[]) ON CONFLICT (name) DO NOTHING;",
+        tags
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    sqlx::query!(
+        "INSERT INTO quote_tags (quote_id, tag_id) SELECT $1, id FROM tags WHERE name = ANY($2);",
+        id,
+        tags
+    )
+    .execute(conn)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(())
+}
+
+/// Replaces the contents of the quote as a new version and records it in the history
+#[instrument(ret, err(Debug), skip(conn))]
+async fn update_quote(
+    conn: &mut PgConnection,
+    id: &Uuid,
+    author: &str,
+    quote: &str,
+    tags: Option<&[String]>,
+) -> actix_web::Result<Quote> {
+    let query_result = sqlx::query!(
+        "UPDATE quotes 
+        SET
+        author = $1, 
+        quote = $2,
+        version = version + 1
+        WHERE id = $3 AND deleted_at IS NULL;",
+        author,
+        quote,
+        id
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    if query_result.rows_affected() != 1 {
+        return Err(error::ErrorNotFound("id not found"));
+    }
+    if let Some(tags) = tags {
+        set_tags(conn, id, tags).await?;
+    }
+    let quote = get_quote(&mut *conn, id).await?;
+    record_version(conn, &quote).await?;
+    Ok(quote)
+}
+
+/// Creates a new quote and records it as the first version in its history
+#[instrument(ret, err(Debug), skip(conn))]
+async fn insert_quote(conn: &mut PgConnection, draft: &DraftQuote) -> actix_web::Result<Quote> {
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO quotes 
+        (id, author, quote, created_at) 
+        values ($1, $2, $3, clock_timestamp());",
+        id,
+        draft.author,
+        draft.quote
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    if let Some(tags) = draft.tags() {
+        set_tags(conn, &id, &tags).await?;
+    }
+    let quote = get_quote(&mut *conn, &id).await?;
+    record_version(conn, &quote).await?;
+    Ok(quote)
+}
+
+#[async_trait]
+impl QuoteStore for PostgresStore {
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn get(&self, id: &Uuid) -> actix_web::Result<Quote> {
+        get_quote(&self.pool, id).await
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn insert_all(&self, drafts: &[DraftQuote]) -> actix_web::Result<Vec<Quote>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        let mut quotes = Vec::with_capacity(drafts.len());
+        for draft in drafts {
+            quotes.push(insert_quote(&mut tx, draft).await?);
+        }
+        tx.commit().await.map_err(error::ErrorInternalServerError)?;
+        Ok(quotes)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn update(
+        &self,
+        id: &Uuid,
+        draft: &DraftQuote,
+        precondition: &Precondition,
+    ) -> actix_web::Result<Quote> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        precondition.check(&get_quote_for_update(&mut tx, id).await?)?;
+        let tags = draft.tags();
+        let quote = update_quote(&mut tx, id, &draft.author, &draft.quote, tags.as_deref()).await?;
+        tx.commit().await.map_err(error::ErrorInternalServerError)?;
+        Ok(quote)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn revert(&self, id: &Uuid, version: i32) -> actix_web::Result<Quote> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        let old = sqlx::query_as!(
+            QuoteVersion,
+            "SELECT version, author, quote, created_at FROM quote_versions
+            WHERE quote_id = $1 AND version = $2;",
+            id,
+            version
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .context("version not found")
+        .map_err(error::ErrorNotFound)?;
+        let quote = update_quote(&mut tx, id, &old.author, &old.quote, None).await?;
+        tx.commit().await.map_err(error::ErrorInternalServerError)?;
+        Ok(quote)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn delete(&self, id: &Uuid, precondition: &Precondition) -> actix_web::Result<Quote> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        let quote = get_quote_for_update(&mut tx, id).await?;
+        precondition.check(&quote)?;
+        sqlx::query!(
+            "UPDATE quotes SET deleted_at = CURRENT_TIMESTAMP WHERE id = $1",
+            id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+        tx.commit().await.map_err(error::ErrorInternalServerError)?;
+        Ok(quote)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn truncate(&self) -> actix_web::Result<()> {
+        sqlx::query!("UPDATE quotes SET deleted_at = CURRENT_TIMESTAMP WHERE deleted_at IS NULL;")
+            .execute(&self.pool)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        Ok(())
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn list(
+        &self,
+        filter: &QuoteFilter,
+        offset: i64,
+        limit: i64,
+    ) -> actix_web::Result<Vec<Quote>> {
+        sqlx::query_as!(
+            Quote,
+            r#"SELECT id, author, quote, created_at, version,
+            ARRAY(
+                SELECT t.name FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+                WHERE qt.quote_id = quotes.id ORDER BY t.name
+            ) as "tags!"
+            FROM quotes
+            WHERE deleted_at IS NULL
+            AND ($1::TEXT IS NULL OR author = $1)
+            AND ($2::TEXT IS NULL OR search @@ websearch_to_tsquery('english', $2))
+            AND ($3::TEXT IS NULL OR EXISTS (
+                SELECT 1 FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+                WHERE qt.quote_id = quotes.id AND t.name = $3
+            ))
+            ORDER BY created_at, id
+            LIMIT $4 OFFSET $5;"#,
+            filter.author,
+            filter.q,
+            filter.tag,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    /// Uses the `search` column so words match regardless of their form (eg. "run" and "running")
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn search(&self, q: &str, limit: i64) -> actix_web::Result<Vec<SearchResult>> {
+        let options = format!(r#"StartSel="{HIGHLIGHT_START}", StopSel="{HIGHLIGHT_STOP}""#);
+        let rows = sqlx::query!(
+            r#"SELECT id, author, quote, created_at, version,
+            ARRAY(
+                SELECT t.name FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+                WHERE qt.quote_id = quotes.id ORDER BY t.name
+            ) as "tags!",
+            ts_rank(search, query) as "rank!",
+            ts_headline('english', quote, query, $2) as "snippet!"
+            FROM quotes, websearch_to_tsquery('english', $1) query
+            WHERE deleted_at IS NULL AND search @@ query
+            ORDER BY 7 DESC, created_at, id
+            LIMIT $3;"#,
+            q,
+            options,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+        Ok(rows
+            .into_iter()
+            .map(|row| SearchResult {
+                quote: Quote {
+                    id: row.id,
+                    author: row.author,
+                    quote: row.quote,
+                    created_at: row.created_at,
+                    version: row.version,
+                    tags: row.tags,
+                },
+                rank: row.rank,
+                snippet: row.snippet,
+            })
+            .collect())
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn count(&self, filter: &QuoteFilter) -> actix_web::Result<i64> {
+        sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM quotes
+            WHERE deleted_at IS NULL
+            AND ($1::TEXT IS NULL OR author = $1)
+            AND ($2::TEXT IS NULL OR search @@ websearch_to_tsquery('english', $2))
+            AND ($3::TEXT IS NULL OR EXISTS (
+                SELECT 1 FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+                WHERE qt.quote_id = quotes.id AND t.name = $3
+            ));"#,
+            filter.author,
+            filter.q,
+            filter.tag
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn list_after(
+        &self,
+        filter: &QuoteFilter,
+        after: Option<(chrono::DateTime<Utc>, Uuid)>,
+        limit: i64,
+    ) -> actix_web::Result<Vec<Quote>> {
+        let (after_created_at, after_id) = after.unzip();
+        sqlx::query_as!(
+            Quote,
+            r#"SELECT id, author, quote, created_at, version,
+            ARRAY(
+                SELECT t.name FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+                WHERE qt.quote_id = quotes.id ORDER BY t.name
+            ) as "tags!"
+            FROM quotes
+            WHERE deleted_at IS NULL
+            AND ($1::TEXT IS NULL OR author = $1)
+            AND ($2::TEXT IS NULL OR search @@ websearch_to_tsquery('english', $2))
+            AND ($3::TEXT IS NULL OR EXISTS (
+                SELECT 1 FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+                WHERE qt.quote_id = quotes.id AND t.name = $3
+            ))
+            AND ($4::TIMESTAMPTZ IS NULL OR (created_at, id) > ($4, $5))
+            ORDER BY created_at, id
+            LIMIT $6;"#,
+            filter.author,
+            filter.q,
+            filter.tag,
+            after_created_at,
+            after_id,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn tags(&self) -> actix_web::Result<Vec<TagCount>> {
+        sqlx::query_as!(
+            TagCount,
+            r#"SELECT t.name as tag, COUNT(*) as "count!"
+            FROM tags t
+            JOIN quote_tags qt ON qt.tag_id = t.id
+            JOIN quotes q ON q.id = qt.quote_id
+            WHERE q.deleted_at IS NULL
+            GROUP BY t.name
+            ORDER BY 2 DESC, t.name;"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn authors(&self) -> actix_web::Result<Vec<AuthorStats>> {
+        sqlx::query_as!(
+            AuthorStats,
+            r#"SELECT
+            author,
+            COUNT(*) as "quotes!",
+            MIN(created_at) as "first_created_at!",
+            MAX(created_at) as "last_created_at!",
+            SUM(version - 1)::BIGINT as "edits!"
+            FROM quotes
+            WHERE deleted_at IS NULL
+            GROUP BY author
+            ORDER BY 2 DESC, author;"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn history(&self, id: &Uuid) -> actix_web::Result<Vec<QuoteVersion>> {
+        self.get(id).await?;
+        sqlx::query_as!(
+            QuoteVersion,
+            "SELECT version, author, quote, created_at FROM quote_versions
+            WHERE quote_id = $1
+            ORDER BY version;",
+            id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn find_duplicate(
+        &self,
+        author: &str,
+        quote: &str,
+        except: Option<&Uuid>,
+    ) -> actix_web::Result<Option<Uuid>> {
+        sqlx::query_scalar!(
+            "SELECT id FROM quotes
+            WHERE deleted_at IS NULL AND author = $1 AND quote = $2
+            AND ($3::UUID IS NULL OR id <> $3)
+            LIMIT 1;",
+            author,
+            quote,
+            except
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn trash(&self) -> actix_web::Result<Vec<TrashedQuote>> {
+        sqlx::query_as!(
+            TrashedQuote,
+            r#"SELECT id, author, quote, created_at, version, deleted_at as "deleted_at!"
+            FROM quotes
+            WHERE deleted_at IS NOT NULL
+            ORDER BY deleted_at DESC, id;"#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn restore(&self, id: &Uuid) -> actix_web::Result<Quote> {
+        let query_result = sqlx::query!(
+            "UPDATE quotes SET deleted_at = NULL
+            WHERE id = $1 AND deleted_at IS NOT NULL;",
+            id
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+        if query_result.rows_affected() != 1 {
+            return Err(error::ErrorNotFound("id not found in trash"));
+        }
+        get_quote(&self.pool, id).await
+    }
+}
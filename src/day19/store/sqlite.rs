@@ -0,0 +1,472 @@
+use actix_web::error;
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    SqliteConnection, SqlitePool,
+};
+use std::str::FromStr;
+use tracing::instrument;
+use uuid::Uuid;
+
+use super::{creation_times, Precondition, QuoteFilter, QuoteStore};
+use crate::day19::{AuthorStats, DraftQuote, Quote, QuoteVersion, TagCount, TrashedQuote};
+
+/// Columns needed for a [`Quote`], SQLite has no arrays so the tags are collected as JSON
+macro_rules! quote_columns {
+    () => {
+        "id, author, quote, created_at, version,
+        (
+            SELECT json_group_array(name) FROM (
+                SELECT t.name FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+                WHERE qt.quote_id = quotes.id ORDER BY t.name
+            )
+        ) AS tags"
+    };
+}
+
+/// Stores quotes in SQLite
+///
+/// The `query!` macros are checked against Postgres so queries here are only checked at runtime
+#[derive(Debug)]
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    /// The schema is created when the first connection is opened
+    pub fn new_lazy(url: &str) -> anyhow::Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)
+            .context("invalid SQLite URL")?
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            // A single connection that never closes keeps `sqlite::memory:` databases alive
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .after_connect(|conn, _meta| {
+                Box::pin(async move {
+                    // `run` can't be used here, see the comment on `run_direct` in sqlx
+                    sqlx::migrate!("./migrations_sqlite")
+                        .run_direct(conn)
+                        .await
+                        .map_err(|e| sqlx::Error::Migrate(Box::new(e)))
+                })
+            })
+            .connect_lazy_with(options);
+        Ok(Self { pool })
+    }
+
+    /// Quotes matching the filter created after the one identified by `after`, in the order
+    /// they were created
+    ///
+    /// SQLite has no full-text search set up so the words in `q` are matched here instead, which
+    /// means fetching every quote matching the rest of the filter
+    async fn matching(
+        &self,
+        filter: &QuoteFilter,
+        after: Option<(chrono::DateTime<Utc>, Uuid)>,
+        offset: i64,
+        limit: i64,
+    ) -> actix_web::Result<Vec<Quote>> {
+        let (after_created_at, after_id) = after.unzip();
+        // A negative limit means no limit in SQLite
+        let (sql_offset, sql_limit) = match filter.q {
+            Some(_) => (0, -1),
+            None => (offset, limit),
+        };
+        let quotes: Vec<Quote> = sqlx::query_as(concat!(
+            "SELECT ",
+            quote_columns!(),
+            " FROM quotes
+            WHERE deleted_at IS NULL
+            AND (?1 IS NULL OR author = ?1)
+            AND (?2 IS NULL OR EXISTS (
+                SELECT 1 FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+                WHERE qt.quote_id = quotes.id AND t.name = ?2
+            ))
+            AND (?3 IS NULL OR (created_at, id) > (?3, ?4))
+            ORDER BY created_at, id
+            LIMIT ?5 OFFSET ?6;"
+        ))
+        .bind(&filter.author)
+        .bind(&filter.tag)
+        .bind(after_created_at)
+        .bind(after_id)
+        .bind(sql_limit)
+        .bind(sql_offset)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+        if filter.q.is_none() {
+            return Ok(quotes);
+        }
+        Ok(quotes
+            .into_iter()
+            .filter(|quote| filter.matches(quote))
+            .skip(offset.try_into().unwrap_or_default())
+            .take(limit.try_into().unwrap_or_default())
+            .collect())
+    }
+}
+
+#[instrument(ret, err(Debug), skip(conn))]
+async fn get_quote(conn: &mut SqliteConnection, id: &Uuid) -> actix_web::Result<Quote> {
+    sqlx::query_as(concat!(
+        "SELECT ",
+        quote_columns!(),
+        " FROM quotes WHERE id = ?1 AND deleted_at IS NULL;"
+    ))
+    .bind(id)
+    .fetch_optional(conn)
+    .await
+    .map_err(error::ErrorInternalServerError)?
+    .context("id not found")
+    .map_err(error::ErrorNotFound)
+}
+
+/// Saves the quote's current contents as an entry in its history
+#[instrument(ret, err(Debug), skip(conn))]
+async fn record_version(
+    conn: &mut SqliteConnection,
+    quote: &Quote,
+    created_at: chrono::DateTime<Utc>,
+) -> actix_web::Result<()> {
+    sqlx::query(
+        "INSERT INTO quote_versions
+        (quote_id, version, author, quote, created_at)
+        values (?1, ?2, ?3, ?4, ?5);",
+    )
+    .bind(quote.id)
+    .bind(quote.version)
+    .bind(&quote.author)
+    .bind(&quote.quote)
+    .bind(created_at)
+    .execute(conn)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(())
+}
+
+/// Replaces all the tags on the quote, creating any tags that don't exist yet
+#[instrument(ret, err(Debug), skip(conn))]
+async fn set_tags(
+    conn: &mut SqliteConnection,
+    id: &Uuid,
+    tags: &[String],
+) -> actix_web::Result<()> {
+    sqlx::query("DELETE FROM quote_tags WHERE quote_id = ?1;")
+        .bind(id)
+        .execute(&mut *conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    for tag in tags {
+        sqlx::query("INSERT OR IGNORE INTO tags (name) values (?1);")
+            .bind(tag)
+            .execute(&mut *conn)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        sqlx::query(
+            "INSERT INTO quote_tags (quote_id, tag_id) SELECT ?1, id FROM tags WHERE name = ?2;",
+        )
+        .bind(id)
+        .bind(tag)
+        .execute(&mut *conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    }
+    Ok(())
+}
+
+/// Replaces the contents of the quote as a new version and records it in the history
+#[instrument(ret, err(Debug), skip(conn))]
+async fn update_quote(
+    conn: &mut SqliteConnection,
+    id: &Uuid,
+    author: &str,
+    quote: &str,
+    tags: Option<&[String]>,
+) -> actix_web::Result<Quote> {
+    let query_result = sqlx::query(
+        "UPDATE quotes
+        SET
+        author = ?1,
+        quote = ?2,
+        version = version + 1
+        WHERE id = ?3 AND deleted_at IS NULL;",
+    )
+    .bind(author)
+    .bind(quote)
+    .bind(id)
+    .execute(&mut *conn)
+    .await
+    .map_err(error::ErrorInternalServerError)?;
+    if query_result.rows_affected() != 1 {
+        return Err(error::ErrorNotFound("id not found"));
+    }
+    if let Some(tags) = tags {
+        set_tags(conn, id, tags).await?;
+    }
+    let quote = get_quote(conn, id).await?;
+    record_version(conn, &quote, Utc::now()).await?;
+    Ok(quote)
+}
+
+#[async_trait]
+impl QuoteStore for SqliteStore {
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn get(&self, id: &Uuid) -> actix_web::Result<Quote> {
+        let mut conn = self
+            .pool
+            .acquire()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        get_quote(&mut conn, id).await
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn insert_all(&self, drafts: &[DraftQuote]) -> actix_web::Result<Vec<Quote>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        let mut quotes = Vec::with_capacity(drafts.len());
+        for (draft, created_at) in drafts.iter().zip(creation_times()) {
+            let id = Uuid::new_v4();
+            sqlx::query(
+                "INSERT INTO quotes
+                (id, author, quote, created_at)
+                values (?1, ?2, ?3, ?4);",
+            )
+            .bind(id)
+            .bind(&draft.author)
+            .bind(&draft.quote)
+            .bind(created_at)
+            .execute(&mut *tx)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+            if let Some(tags) = draft.tags() {
+                set_tags(&mut tx, &id, &tags).await?;
+            }
+            let quote = get_quote(&mut tx, &id).await?;
+            record_version(&mut tx, &quote, created_at).await?;
+            quotes.push(quote);
+        }
+        tx.commit().await.map_err(error::ErrorInternalServerError)?;
+        Ok(quotes)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn update(
+        &self,
+        id: &Uuid,
+        draft: &DraftQuote,
+        precondition: &Precondition,
+    ) -> actix_web::Result<Quote> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        precondition.check(&get_quote(&mut tx, id).await?)?;
+        let tags = draft.tags();
+        let quote = update_quote(&mut tx, id, &draft.author, &draft.quote, tags.as_deref()).await?;
+        tx.commit().await.map_err(error::ErrorInternalServerError)?;
+        Ok(quote)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn revert(&self, id: &Uuid, version: i32) -> actix_web::Result<Quote> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        let old: QuoteVersion = sqlx::query_as(
+            "SELECT version, author, quote, created_at FROM quote_versions
+            WHERE quote_id = ?1 AND version = ?2;",
+        )
+        .bind(id)
+        .bind(version)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .context("version not found")
+        .map_err(error::ErrorNotFound)?;
+        let quote = update_quote(&mut tx, id, &old.author, &old.quote, None).await?;
+        tx.commit().await.map_err(error::ErrorInternalServerError)?;
+        Ok(quote)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn delete(&self, id: &Uuid, precondition: &Precondition) -> actix_web::Result<Quote> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        let quote = get_quote(&mut tx, id).await?;
+        precondition.check(&quote)?;
+        sqlx::query("UPDATE quotes SET deleted_at = ?1 WHERE id = ?2;")
+            .bind(Utc::now())
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        tx.commit().await.map_err(error::ErrorInternalServerError)?;
+        Ok(quote)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn truncate(&self) -> actix_web::Result<()> {
+        sqlx::query("UPDATE quotes SET deleted_at = ?1 WHERE deleted_at IS NULL;")
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+        Ok(())
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn list(
+        &self,
+        filter: &QuoteFilter,
+        offset: i64,
+        limit: i64,
+    ) -> actix_web::Result<Vec<Quote>> {
+        self.matching(filter, None, offset, limit).await
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn count(&self, filter: &QuoteFilter) -> actix_web::Result<i64> {
+        if filter.q.is_some() {
+            let quotes = self.matching(filter, None, 0, i64::MAX).await?;
+            return Ok(quotes.len().try_into().unwrap_or(i64::MAX));
+        }
+        sqlx::query_scalar(
+            "SELECT COUNT(*) FROM quotes
+            WHERE deleted_at IS NULL
+            AND (?1 IS NULL OR author = ?1)
+            AND (?2 IS NULL OR EXISTS (
+                SELECT 1 FROM quote_tags qt JOIN tags t ON t.id = qt.tag_id
+                WHERE qt.quote_id = quotes.id AND t.name = ?2
+            ));",
+        )
+        .bind(&filter.author)
+        .bind(&filter.tag)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn list_after(
+        &self,
+        filter: &QuoteFilter,
+        after: Option<(chrono::DateTime<Utc>, Uuid)>,
+        limit: i64,
+    ) -> actix_web::Result<Vec<Quote>> {
+        self.matching(filter, after, 0, limit).await
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn tags(&self) -> actix_web::Result<Vec<TagCount>> {
+        sqlx::query_as(
+            "SELECT t.name AS tag, COUNT(*) AS count
+            FROM tags t
+            JOIN quote_tags qt ON qt.tag_id = t.id
+            JOIN quotes q ON q.id = qt.quote_id
+            WHERE q.deleted_at IS NULL
+            GROUP BY t.name
+            ORDER BY 2 DESC, t.name;",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn authors(&self) -> actix_web::Result<Vec<AuthorStats>> {
+        sqlx::query_as(
+            "SELECT
+            author,
+            COUNT(*) AS quotes,
+            MIN(created_at) AS first_created_at,
+            MAX(created_at) AS last_created_at,
+            SUM(version - 1) AS edits
+            FROM quotes
+            WHERE deleted_at IS NULL
+            GROUP BY author
+            ORDER BY 2 DESC, author;",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn history(&self, id: &Uuid) -> actix_web::Result<Vec<QuoteVersion>> {
+        self.get(id).await?;
+        sqlx::query_as(
+            "SELECT version, author, quote, created_at FROM quote_versions
+            WHERE quote_id = ?1
+            ORDER BY version;",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn find_duplicate(
+        &self,
+        author: &str,
+        quote: &str,
+        except: Option<&Uuid>,
+    ) -> actix_web::Result<Option<Uuid>> {
+        sqlx::query_scalar(
+            "SELECT id FROM quotes
+            WHERE deleted_at IS NULL AND author = ?1 AND quote = ?2
+            AND (?3 IS NULL OR id <> ?3)
+            LIMIT 1;",
+        )
+        .bind(author)
+        .bind(quote)
+        .bind(except)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn trash(&self) -> actix_web::Result<Vec<TrashedQuote>> {
+        sqlx::query_as(
+            "SELECT id, author, quote, created_at, version, deleted_at
+            FROM quotes
+            WHERE deleted_at IS NOT NULL
+            ORDER BY deleted_at DESC, id;",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)
+    }
+
+    #[instrument(ret, err(Debug), skip(self))]
+    async fn restore(&self, id: &Uuid) -> actix_web::Result<Quote> {
+        let query_result = sqlx::query(
+            "UPDATE quotes SET deleted_at = NULL
+            WHERE id = ?1 AND deleted_at IS NOT NULL;",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+        if query_result.rows_affected() != 1 {
+            return Err(error::ErrorNotFound("id not found in trash"));
+        }
+        self.get(id).await
+    }
+}
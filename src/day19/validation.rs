@@ -0,0 +1,294 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt::Display;
+use unicode_normalization::UnicodeNormalization as _;
+use uuid::Uuid;
+
+use super::{store::QuoteStore, DraftQuote};
+
+const MAX_AUTHOR_CHARS: usize = 100;
+const MAX_QUOTE_CHARS: usize = 1_000;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_CHARS: usize = 32;
+
+/// Why a single field of a [`DraftQuote`] was rejected
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    field: &'static str,
+    message: String,
+}
+
+/// Every problem found with a [`DraftQuote`], returned as 422 Unprocessable Entity
+#[derive(Debug, Serialize)]
+pub struct ValidationError {
+    errors: Vec<FieldError>,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}: {}", error.field, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl ResponseError for ValidationError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNPROCESSABLE_ENTITY
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self)
+    }
+}
+
+/// Trims the text and converts it to Unicode Normalization Form C
+///
+/// Without this the same text typed on different systems could be stored as different strings
+pub fn normalise_text(text: &str) -> String {
+    text.trim().nfc().collect()
+}
+
+fn check_length(errors: &mut Vec<FieldError>, field: &'static str, value: &str, max_chars: usize) {
+    let message = if value.is_empty() {
+        "must not be empty".to_string()
+    } else if value.chars().count() > max_chars {
+        format!("must be at most {max_chars} characters")
+    } else {
+        return;
+    };
+    errors.push(FieldError { field, message });
+}
+
+impl DraftQuote {
+    /// Normalises every field and checks their lengths, without consulting the store
+    pub fn normalised(self) -> Result<Self, ValidationError> {
+        let draft = Self {
+            author: normalise_text(&self.author),
+            quote: normalise_text(&self.quote),
+            tags: self.tags(),
+        };
+
+        let mut errors = vec![];
+        check_length(&mut errors, "author", &draft.author, MAX_AUTHOR_CHARS);
+        check_length(&mut errors, "quote", &draft.quote, MAX_QUOTE_CHARS);
+        if let Some(tags) = &draft.tags {
+            if tags.len() > MAX_TAGS {
+                errors.push(FieldError {
+                    field: "tags",
+                    message: format!("must have at most {MAX_TAGS} tags"),
+                });
+            }
+            if let Some(tag) = tags.iter().find(|tag| tag.chars().count() > MAX_TAG_CHARS) {
+                errors.push(FieldError {
+                    field: "tags",
+                    message: format!("tag {tag:?} must be at most {MAX_TAG_CHARS} characters"),
+                });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(draft)
+        } else {
+            Err(ValidationError { errors })
+        }
+    }
+
+    /// Normalises the draft and rejects it if it is invalid or duplicates another quote
+    ///
+    /// `existing` is the quote being updated, it is allowed to match itself
+    pub async fn validated(
+        self,
+        store: &dyn QuoteStore,
+        existing: Option<&Uuid>,
+    ) -> actix_web::Result<Self> {
+        let draft = self.normalised()?;
+        reject_duplicate(store, &draft.author, &draft.quote, existing).await?;
+        Ok(draft)
+    }
+}
+
+/// Fails if a quote other than `existing` already has exactly this author and text
+///
+/// Also used when reverting or restoring a quote, which brings back text without a draft
+pub async fn reject_duplicate(
+    store: &dyn QuoteStore,
+    author: &str,
+    quote: &str,
+    existing: Option<&Uuid>,
+) -> actix_web::Result<()> {
+    match store.find_duplicate(author, quote, existing).await? {
+        Some(id) => Err(ValidationError {
+            errors: vec![FieldError {
+                field: "quote",
+                message: format!("duplicates quote {id} by the same author"),
+            }],
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::day19::{events::QuoteEvents, restore, revert, store::MemoryStore};
+    use actix_web::{
+        body::to_bytes,
+        test::{call_service, init_service, TestRequest},
+        web, App,
+    };
+    use std::sync::Arc;
+
+    fn draft(author: &str, quote: &str) -> DraftQuote {
+        DraftQuote {
+            author: author.to_string(),
+            quote: quote.to_string(),
+            tags: None,
+        }
+    }
+
+    fn messages(error: ValidationError) -> Vec<(&'static str, String)> {
+        error
+            .errors
+            .into_iter()
+            .map(|error| (error.field, error.message))
+            .collect()
+    }
+
+    #[test]
+    fn normalises_whitespace_and_unicode() {
+        let draft = DraftQuote {
+            author: "  Cafe\u{301} Owner\n".to_string(),
+            quote: "\tHo ho ho ".to_string(),
+            tags: Some(vec![
+                " Cheer ".to_string(),
+                "cheer".to_string(),
+                " ".to_string(),
+            ]),
+        }
+        .normalised()
+        .unwrap();
+        assert_eq!(draft.author, "Caf\u{e9} Owner");
+        assert_eq!(draft.quote, "Ho ho ho");
+        assert_eq!(draft.tags, Some(vec!["cheer".to_string()]));
+    }
+
+    #[test]
+    fn rejects_empty_and_long_fields() {
+        let error = draft(" \t", "").normalised().unwrap_err();
+        assert_eq!(
+            messages(error),
+            [
+                ("author", "must not be empty".to_string()),
+                ("quote", "must not be empty".to_string())
+            ]
+        );
+
+        let error = draft(
+            &"a".repeat(MAX_AUTHOR_CHARS + 1),
+            &"b".repeat(MAX_QUOTE_CHARS + 1),
+        )
+        .normalised()
+        .unwrap_err();
+        assert_eq!(
+            messages(error),
+            [
+                ("author", "must be at most 100 characters".to_string()),
+                ("quote", "must be at most 1000 characters".to_string())
+            ]
+        );
+
+        // Lengths are counted in characters, not bytes
+        let longest = draft(&"é".repeat(MAX_AUTHOR_CHARS), &"é".repeat(MAX_QUOTE_CHARS));
+        assert!(longest.normalised().is_ok());
+    }
+
+    #[actix_web::test]
+    async fn errors_are_keyed_by_field() {
+        let error = draft("", "Ho ho ho").normalised().unwrap_err();
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "errors": [{"field": "author", "message": "must not be empty"}]
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn rejects_duplicates() {
+        let store = MemoryStore::default();
+        let santa = draft("Santa", "Ho ho ho")
+            .validated(&store, None)
+            .await
+            .unwrap();
+        let santa = store.insert(&santa).await.unwrap();
+
+        let error = draft(" Santa", "Ho ho ho ")
+            .validated(&store, None)
+            .await
+            .unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), 422);
+        // Updating a quote without changing it isn't a duplicate of itself
+        draft("Santa", "Ho ho ho")
+            .validated(&store, Some(&santa.id))
+            .await
+            .unwrap();
+        // Another author can say the same
+        draft("Elf", "Ho ho ho")
+            .validated(&store, None)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn rejects_duplicates_when_restoring_and_reverting() {
+        let store: Arc<dyn QuoteStore> = Arc::new(MemoryStore::default());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::from(store.clone()))
+                .app_data(QuoteEvents::new_wrapped(None))
+                .route("/restore/{id}", web::put().to(restore))
+                .route("/revert/{id}/{version}", web::put().to(revert)),
+        )
+        .await;
+        let status = |uri: String| {
+            let app = &app;
+            async move {
+                let request = TestRequest::put().uri(&uri).to_request();
+                call_service(app, request).await.status()
+            }
+        };
+
+        let removed = store.insert(&draft("Santa", "Ho ho ho")).await.unwrap();
+        store
+            .delete(&removed.id, &Default::default())
+            .await
+            .unwrap();
+        let added = store.insert(&draft("Santa", "Ho ho ho")).await.unwrap();
+        assert_eq!(status(format!("/restore/{}", removed.id)).await, 422);
+        store.delete(&added.id, &Default::default()).await.unwrap();
+        assert_eq!(status(format!("/restore/{}", removed.id)).await, 200);
+
+        let changed = store.insert(&draft("Elf", "Jingle")).await.unwrap();
+        store
+            .update(
+                &changed.id,
+                &draft("Elf", "Jingle bells"),
+                &Default::default(),
+            )
+            .await
+            .unwrap();
+        store.insert(&draft("Elf", "Jingle")).await.unwrap();
+        assert_eq!(status(format!("/revert/{}/1", changed.id)).await, 422);
+        assert_eq!(status(format!("/revert/{}/2", changed.id)).await, 200);
+    }
+}
@@ -4,6 +4,9 @@ use actix_web::{
     web::{self, ServiceConfig},
     HttpRequest, HttpResponse,
 };
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use std::time::Duration;
+use tracing::warn;
 
 mod day02;
 mod day05;
@@ -29,20 +32,62 @@ fn modify_service_config(cfg: &mut ServiceConfig) {
     cfg.default_service(web::route().to(not_found).wrap(Logger::default()));
 }
 
+/// Same as the pools shuttle hands out
+const MAX_CONNECTIONS: u32 = 5;
+
+/// How long to wait for a connection when Postgres is optional, so it being down fails quickly
+const OPTIONAL_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Connects to Postgres and runs the migrations
+///
+/// Postgres is only required when the day 19 quotes are kept there. Otherwise connecting waits
+/// until a connection is needed and Postgres being unavailable is only logged, the features that
+/// use it (eg. saving connect four games) fail until it is back
+pub async fn connect(url: &str) -> PgPool {
+    let options = PgPoolOptions::new().max_connections(MAX_CONNECTIONS);
+    if day19::needs_postgres() {
+        let pool = options
+            .connect(url)
+            .await
+            .expect("Failed to connect to Postgres");
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .expect("Failed to run migrations");
+        return pool;
+    }
+    let pool = options
+        .acquire_timeout(OPTIONAL_ACQUIRE_TIMEOUT)
+        .connect_lazy(url)
+        .expect("Invalid Postgres URL");
+    if let Err(e) = sqlx::migrate!().run(&pool).await {
+        warn!(?e, "Postgres is unavailable, continuing without it");
+    }
+    pool
+}
+
 /// This function is called once and returns a closure that is called once per worker
-pub fn setup_closure(
-    pool: sqlx::PgPool,
+pub async fn setup_closure(
+    pool: PgPool,
 ) -> impl FnOnce(&mut ServiceConfig) + Send + Clone + 'static {
     // Code that should run exactly once
+    let day19_store = day19::quote_store(pool.clone());
+    let day19_events = day19::quote_events(pool.clone());
+    let day19_keys = day19::api_keys(pool.clone());
+    let day12_data = day12::app_data(pool.clone()).await;
     let pool = web::Data::new(pool);
     let day09_data = day09::app_data();
-    let day12_data = day12::app_data();
+    let day19_data = day19::app_data();
 
     // Closure that is returned
     |cfg: &mut ServiceConfig| {
         cfg.app_data(pool);
         cfg.app_data(day09_data);
         cfg.app_data(day12_data);
+        cfg.app_data(day19_data);
+        cfg.app_data(day19_store);
+        cfg.app_data(day19_events);
+        cfg.app_data(day19_keys);
 
         modify_service_config(cfg);
     }